@@ -1,7 +1,4 @@
 use anyhow::Result;
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
-};
 use std::{env, path::PathBuf};
 
 fn main() -> Result<()> {
@@ -19,11 +16,5 @@ fn main() -> Result<()> {
         .compile(&["proto/creature.proto"], &["proto"])
         .unwrap_or_else(|e| panic!("protobuf compile error: {:?}", e));
 
-    // Setup sentence embeddings model
-    let _model = SentenceEmbeddingsBuilder::remote(
-        SentenceEmbeddingsModelType::AllMiniLmL6V2,
-    )
-    .create_model()?;
-
     Ok(())
 }
@@ -23,6 +23,17 @@ impl FiniteQueueMemory {
             max_size,
         }
     }
+
+    /// Changes the size, dropping the oldest messages beyond it.
+    pub(crate) fn resize(
+        &mut self,
+        max_size: usize,
+    ) {
+        self.max_size = max_size;
+        while self.memories.len() > self.max_size {
+            self.memories.pop_front();
+        }
+    }
 }
 
 impl Memory for FiniteQueueMemory {
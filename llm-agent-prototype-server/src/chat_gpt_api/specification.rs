@@ -1,8 +1,8 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub(crate) enum Model {
     Gpt35Turbo,
     Gpt35Turbo0613,
@@ -30,19 +30,19 @@ impl Model {
         }
     }
 
-    // pub(crate) fn parse_to_model(input: &str) -> Result<Model> {
-    //     match input {
-    //         "gpt-3.5-turbo" => Ok(Model::Gpt35Turbo),
-    //         "gpt-3.5-turbo-0613" => Ok(Model::Gpt35Turbo0613),
-    //         "gpt-3.5-turbo-16k" => Ok(Model::Gpt35Turbo16k),
-    //         "gpt-3.5-turbo-16k-0613" => Ok(Model::Gpt35Turbo16k0613),
-    //         "gpt-4" => Ok(Model::Gpt4),
-    //         "gpt-4-0613" => Ok(Model::Gpt40613),
-    //         "gpt-4-32k" => Ok(Model::Gpt432k),
-    //         "gpt-4-32k-0613" => Ok(Model::Gpt432k0613),
-    //         _ => Err(anyhow!("Invalid model")),
-    //     }
-    // }
+    pub(crate) fn parse_to_model(input: &str) -> Result<Model> {
+        match input {
+            | "gpt-3.5-turbo" => Ok(Model::Gpt35Turbo),
+            | "gpt-3.5-turbo-0613" => Ok(Model::Gpt35Turbo0613),
+            | "gpt-3.5-turbo-16k" => Ok(Model::Gpt35Turbo16k),
+            | "gpt-3.5-turbo-16k-0613" => Ok(Model::Gpt35Turbo16k0613),
+            | "gpt-4" => Ok(Model::Gpt4),
+            | "gpt-4-0613" => Ok(Model::Gpt40613),
+            | "gpt-4-32k" => Ok(Model::Gpt432k),
+            | "gpt-4-32k-0613" => Ok(Model::Gpt432k0613),
+            | _ => Err(anyhow!("Invalid model: {}", input)),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -0,0 +1,693 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::chat_gpt_api::specification::Model;
+use crate::rate_limit::{Limit, RateLimitConfig};
+use crate::vector_db::embeddings::{EmbeddingBackend, EmbeddingConfig};
+use crate::vector_db::store::{StoreBackend, VectorStoreConfig};
+
+/// Prefix of environment variables overriding settings, with `__` between
+/// nested keys, e.g. `LLM_AGENT_QDRANT__URL`.
+const ENV_PREFIX: &str = "LLM_AGENT_";
+
+/// File read when `--config` is omitted, skipped when missing.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Keys accepted at their former place with a warning.
+const MOVED_KEYS: [(&str, &str); 2] = [
+    ("qdrant.mode", "vector_store.mode"),
+    (
+        "qdrant.on_mismatch",
+        "vector_store.on_mismatch",
+    ),
+];
+
+#[derive(Parser, Debug)]
+#[command(about = "A prototype server for LLM Agent.")]
+pub(crate) struct Cli {
+    /// Configuration file.
+    #[arg(long, value_name = "FILE")]
+    pub(crate) config: Option<PathBuf>,
+    /// Listen address, e.g. 0.0.0.0:50051.
+    #[arg(long)]
+    pub(crate) address: Option<String>,
+    #[arg(long)]
+    pub(crate) qdrant_url: Option<String>,
+    /// How long memories live: persistent, reset or ephemeral.
+    #[arg(long, value_name = "MODE")]
+    pub(crate) memory_mode: Option<String>,
+    /// Directory of the creature definitions.
+    #[arg(long, value_name = "DIRECTORY")]
+    pub(crate) creatures: Option<String>,
+    /// Any setting by its dotted key, e.g. --set creatures.search_limit=20.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    pub(crate) overrides: Vec<String>,
+    /// Prints the effective configuration and exits.
+    #[arg(long)]
+    pub(crate) print_config: bool,
+    #[command(subcommand)]
+    pub(crate) command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Downloads the local embedding model into the models directory and
+    /// checks that it loads, so that the server can run offline.
+    DownloadModel {
+        /// Model name, e.g. distiluse-base-multilingual-cased. Defaults to
+        /// embedding.local_model.
+        #[arg(long)]
+        model: Option<String>,
+        /// Defaults to embedding.models_directory.
+        #[arg(long, value_name = "DIRECTORY")]
+        directory: Option<String>,
+    },
+}
+
+/// Settings of the server, layered from defaults, the configuration file,
+/// environment variables and command line flags in that order.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) qdrant: QdrantConfig,
+    pub(crate) vector_store: VectorStoreConfig,
+    pub(crate) embedding: EmbeddingConfig,
+    pub(crate) creatures: CreaturesConfig,
+    pub(crate) rate_limit: RateLimitConfig,
+    /// Problems found while loading, logged once logging is initialized.
+    #[serde(skip)]
+    pub(crate) warnings: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub(crate) address: String,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0:50051".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QdrantConfig {
+    pub(crate) url: String,
+}
+
+impl Default for QdrantConfig {
+    fn default() -> Self {
+        Self {
+            url: "http://qdrant:6334".to_string(),
+        }
+    }
+}
+
+/// Where creatures are defined and what their definitions default to.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct CreaturesConfig {
+    pub(crate) directory: String,
+    /// Seconds between checks of the definition files for edits.
+    pub(crate) reload_interval_seconds: u64,
+    /// Collection of creatures without their own.
+    pub(crate) shared_collection: String,
+    pub(crate) model: String,
+    /// Number of recent messages sent to the LLM.
+    pub(crate) context_size: usize,
+    /// Number of related memories sent to the LLM.
+    pub(crate) search_limit: u64,
+}
+
+impl Default for CreaturesConfig {
+    fn default() -> Self {
+        Self {
+            directory: "creatures".to_string(),
+            reload_interval_seconds: 2,
+            shared_collection: "long_memory".to_string(),
+            model: "gpt-3.5-turbo-0613".to_string(),
+            context_size: 10,
+            search_limit: 10,
+        }
+    }
+}
+
+impl Config {
+    #[tracing::instrument(name = "config.load", err, skip(cli))]
+    pub(crate) fn load(cli: &Cli) -> Result<Self> {
+        let mut layered = toml::Value::try_from(Config::default())?;
+        let mut warnings = Vec::new();
+
+        let path = cli
+            .config
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+        if cli.config.is_some() || path.exists() {
+            let mut file = read_file(&path)?;
+            move_keys(&mut file, &mut warnings);
+            merge(&mut layered, file);
+        }
+
+        // Other programs may share the prefix, so unknown keys are skipped.
+        let known = known_keys()?;
+        for (name, value) in std::env::vars() {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let key = key
+                .to_lowercase()
+                .replace("__", ".");
+            let key = moved_key(&key, &mut warnings);
+            if !is_known(&known, key) {
+                warnings.push(format!(
+                    "Ignored environment variable {} of no setting",
+                    name
+                ));
+                continue;
+            }
+            set(&mut layered, key, &value)?;
+        }
+
+        for (key, value) in [
+            ("server.address", &cli.address),
+            ("qdrant.url", &cli.qdrant_url),
+            ("vector_store.mode", &cli.memory_mode),
+            ("creatures.directory", &cli.creatures),
+        ] {
+            if let Some(value) = value {
+                set(
+                    &mut layered,
+                    key,
+                    &toml::Value::String(value.clone()).to_string(),
+                )?;
+            }
+        }
+        if let Some(Command::DownloadModel {
+            model,
+            directory,
+        }) = &cli.command
+        {
+            for (key, value) in [
+                ("embedding.local_model", model),
+                ("embedding.models_directory", directory),
+            ] {
+                if let Some(value) = value {
+                    set(
+                        &mut layered,
+                        key,
+                        &toml::Value::String(value.clone()).to_string(),
+                    )?;
+                }
+            }
+        }
+        for assignment in &cli.overrides {
+            let (key, value) = assignment
+                .split_once('=')
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Override must be KEY=VALUE: {}",
+                        assignment
+                    )
+                })?;
+            set(
+                &mut layered,
+                moved_key(key.trim(), &mut warnings),
+                value.trim(),
+            )?;
+        }
+
+        let mut config: Config = layered
+            .try_into()
+            .map_err(|error| anyhow!("Invalid configuration: {}", error))?;
+        config.validate()?;
+        config.warnings = warnings;
+
+        Ok(config)
+    }
+
+    /// Reports every invalid setting at once.
+    pub(crate) fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self
+            .server
+            .address
+            .parse::<SocketAddr>()
+            .is_err()
+        {
+            problems.push(format!(
+                "server.address is not a socket address: {}",
+                self.server.address
+            ));
+        }
+        if self.vector_store.backend == StoreBackend::Qdrant
+            && !self
+                .qdrant
+                .url
+                .starts_with("http://")
+            && !self
+                .qdrant
+                .url
+                .starts_with("https://")
+        {
+            problems.push(format!(
+                "qdrant.url is not an HTTP URL: {}",
+                self.qdrant.url
+            ));
+        }
+        match self.embedding.backend {
+            | EmbeddingBackend::Local => {},
+            | EmbeddingBackend::Remote => {
+                if !self
+                    .embedding
+                    .url
+                    .starts_with("http://")
+                    && !self
+                        .embedding
+                        .url
+                        .starts_with("https://")
+                {
+                    problems.push(format!(
+                        "embedding.url is not an HTTP URL: {}",
+                        self.embedding.url
+                    ));
+                }
+                if self
+                    .embedding
+                    .model
+                    .is_empty()
+                {
+                    problems.push("embedding.model is empty".to_string());
+                }
+            },
+            | EmbeddingBackend::Hashing => {
+                if self.embedding.dimension == 0 {
+                    problems.push(
+                        "embedding.dimension must be positive".to_string(),
+                    );
+                }
+            },
+        }
+        if !Path::new(&self.creatures.directory).is_dir() {
+            problems.push(format!(
+                "creatures.directory is not a directory: {}",
+                self.creatures.directory
+            ));
+        }
+        if self
+            .creatures
+            .reload_interval_seconds
+            == 0
+        {
+            problems.push(
+                "creatures.reload_interval_seconds must be positive"
+                    .to_string(),
+            );
+        }
+        if self
+            .creatures
+            .shared_collection
+            .is_empty()
+        {
+            problems.push("creatures.shared_collection is empty".to_string());
+        }
+        if Model::parse_to_model(&self.creatures.model).is_err() {
+            problems.push(format!(
+                "creatures.model is unknown: {}",
+                self.creatures.model
+            ));
+        }
+        if self.creatures.context_size == 0 {
+            problems
+                .push("creatures.context_size must be positive".to_string());
+        }
+        if self.creatures.search_limit == 0 {
+            problems
+                .push("creatures.search_limit must be positive".to_string());
+        }
+        for (name, limit, is_messages) in [
+            (
+                "per_author_messages",
+                &self
+                    .rate_limit
+                    .per_author_messages,
+                true,
+            ),
+            (
+                "per_author_llm_tokens",
+                &self
+                    .rate_limit
+                    .per_author_llm_tokens,
+                false,
+            ),
+            (
+                "per_connection_messages",
+                &self
+                    .rate_limit
+                    .per_connection_messages,
+                true,
+            ),
+            (
+                "per_connection_llm_tokens",
+                &self
+                    .rate_limit
+                    .per_connection_llm_tokens,
+                false,
+            ),
+        ] {
+            check_limit(name, limit, is_messages, &mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "Invalid configuration:\n  - {}",
+                problems.join("\n  - ")
+            ))
+        }
+    }
+
+    pub(crate) fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+/// Buckets of messages need room for one message, or nothing ever passes.
+fn check_limit(
+    name: &str,
+    limit: &Limit,
+    is_messages: bool,
+    problems: &mut Vec<String>,
+) {
+    if !limit.capacity.is_finite() || limit.capacity <= 0.0 {
+        problems.push(format!(
+            "rate_limit.{}.capacity must be positive",
+            name
+        ));
+    } else if is_messages && limit.capacity < 1.0 {
+        problems.push(format!(
+            "rate_limit.{}.capacity must be at least 1",
+            name
+        ));
+    }
+    if !limit
+        .refill_per_second
+        .is_finite()
+        || limit.refill_per_second < 0.0
+    {
+        problems.push(format!(
+            "rate_limit.{}.refill_per_second must not be negative",
+            name
+        ));
+    }
+}
+
+/// Every setting, including optional ones the defaults leave out.
+fn known_keys() -> Result<toml::Value> {
+    let mut config = Config::default();
+    config
+        .vector_store
+        .snapshot_directory = Some(String::new());
+    config
+        .embedding
+        .models_directory = Some(String::new());
+    config
+        .embedding
+        .cache_directory = Some(String::new());
+
+    Ok(toml::Value::try_from(config)?)
+}
+
+fn is_known(
+    known: &toml::Value,
+    key: &str,
+) -> bool {
+    key.split('.')
+        .try_fold(known, |table, part| table.get(part))
+        .is_some()
+}
+
+/// Current place of a dotted key that has moved.
+fn moved_key<'a>(
+    key: &'a str,
+    warnings: &mut Vec<String>,
+) -> &'a str {
+    match MOVED_KEYS
+        .iter()
+        .find(|(old, _)| *old == key)
+    {
+        | Some((old, new)) => {
+            warnings.push(format!("{} has moved to {}", old, new));
+            new
+        },
+        | None => key,
+    }
+}
+
+/// Moves keys of a configuration file to their current place unless the
+/// file sets both.
+fn move_keys(
+    file: &mut toml::Value,
+    warnings: &mut Vec<String>,
+) {
+    for (old, new) in MOVED_KEYS {
+        let Some((old_table, old_name)) = old.split_once('.') else {
+            continue;
+        };
+        let Some(value) = file
+            .get_mut(old_table)
+            .and_then(|table| table.as_table_mut())
+            .and_then(|table| table.remove(old_name))
+        else {
+            continue;
+        };
+        warnings.push(format!("{} has moved to {}", old, new));
+
+        if is_known(file, new) {
+            continue;
+        }
+        let (new_table, new_name) = new
+            .split_once('.')
+            .unwrap_or(("", new));
+        if let Some(table) = file
+            .as_table_mut()
+            .map(|file| {
+                file.entry(new_table)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            })
+            .and_then(|table| table.as_table_mut())
+        {
+            table.insert(new_name.to_string(), value);
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<toml::Value> {
+    let text = std::fs::read_to_string(path).map_err(|error| {
+        tracing::error!(
+            "Failed to read configuration {}: {:?}",
+            path.display(),
+            error
+        );
+        anyhow!(
+            "Failed to read configuration {}: {}",
+            path.display(),
+            error
+        )
+    })?;
+
+    toml::from_str(&text).map_err(|error| {
+        anyhow!(
+            "Invalid configuration {}: {}",
+            path.display(),
+            error
+        )
+    })
+}
+
+/// Overwrites the tables of `base` by those of `layer`, key by key.
+fn merge(
+    base: &mut toml::Value,
+    layer: toml::Value,
+) {
+    match (base, layer) {
+        | (toml::Value::Table(base), toml::Value::Table(layer)) => {
+            for (key, value) in layer {
+                match base.get_mut(&key) {
+                    | Some(existing) => merge(existing, value),
+                    | None => {
+                        base.insert(key, value);
+                    },
+                }
+            }
+        },
+        | (base, layer) => *base = layer,
+    }
+}
+
+/// Sets a dotted key to a TOML literal, or to a string when the value is
+/// not one, e.g. `qdrant.url=http://localhost:6334`.
+fn set(
+    base: &mut toml::Value,
+    key: &str,
+    raw: &str,
+) -> Result<()> {
+    let value = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(raw.to_string()));
+
+    let mut layer = value;
+    for part in key.rsplit('.') {
+        if part.is_empty() {
+            return Err(anyhow!(
+                "Invalid configuration key: {}",
+                key
+            ));
+        }
+        let mut table = toml::Table::new();
+        table.insert(part.to_string(), layer);
+        layer = toml::Value::Table(table);
+    }
+    merge(base, layer);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn knows_optional_settings() {
+        let known = known_keys().unwrap();
+
+        assert!(is_known(&known, "qdrant.url"));
+        assert!(is_known(
+            &known,
+            "vector_store.snapshot_directory"
+        ));
+        assert!(is_known(
+            &known,
+            "rate_limit.per_author_messages.capacity"
+        ));
+        assert!(!is_known(&known, "qdrant.unknown"));
+        assert!(!is_known(&known, "unknown"));
+    }
+
+    #[test]
+    fn moves_keys_of_files() {
+        let mut file: toml::Value = toml::from_str(
+            "[qdrant]\nurl = \"http://localhost:6334\"\nmode = \"reset\"\n",
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+
+        move_keys(&mut file, &mut warnings);
+
+        assert_eq!(warnings.len(), 1);
+        assert!(file["qdrant"]
+            .get("mode")
+            .is_none());
+        assert_eq!(
+            file["vector_store"]["mode"].as_str(),
+            Some("reset")
+        );
+    }
+
+    #[test]
+    fn prefers_keys_at_their_current_place() {
+        let mut file: toml::Value = toml::from_str(
+            "[qdrant]\non_mismatch = \"reembed\"\n[vector_store]\non_mismatch = \"fail\"\n",
+        )
+        .unwrap();
+
+        move_keys(&mut file, &mut Vec::new());
+
+        assert_eq!(
+            file["vector_store"]["on_mismatch"].as_str(),
+            Some("fail")
+        );
+    }
+
+    #[test]
+    fn moves_dotted_keys() {
+        let mut warnings = Vec::new();
+
+        assert_eq!(
+            moved_key("qdrant.mode", &mut warnings),
+            "vector_store.mode"
+        );
+        assert_eq!(
+            moved_key("qdrant.url", &mut warnings),
+            "qdrant.url"
+        );
+        assert_eq!(warnings.len(), 1);
+    }
+
+    fn limit_problems(
+        capacity: f64,
+        refill_per_second: f64,
+        is_messages: bool,
+    ) -> Vec<String> {
+        let mut problems = Vec::new();
+        check_limit(
+            "per_author_messages",
+            &Limit {
+                capacity,
+                refill_per_second,
+            },
+            is_messages,
+            &mut problems,
+        );
+
+        problems
+    }
+
+    #[test]
+    fn check_limit_accepts_valid_limits() {
+        assert!(limit_problems(1.0, 0.0, true).is_empty());
+        assert!(limit_problems(0.5, 0.1, false).is_empty());
+    }
+
+    #[test]
+    fn check_limit_requires_room_for_one_message() {
+        assert_eq!(limit_problems(0.5, 1.0, true).len(), 1);
+        assert_eq!(limit_problems(0.0, 1.0, false).len(), 1);
+    }
+
+    #[test]
+    fn check_limit_rejects_negative_refill() {
+        assert_eq!(limit_problems(5.0, -0.1, true).len(), 1);
+    }
+
+    #[test]
+    fn check_limit_rejects_numbers_that_are_not_finite() {
+        assert_eq!(
+            limit_problems(f64::NAN, 1.0, true).len(),
+            1
+        );
+        assert_eq!(
+            limit_problems(f64::INFINITY, 1.0, false).len(),
+            1
+        );
+        assert_eq!(
+            limit_problems(5.0, f64::NAN, true).len(),
+            1
+        );
+        assert_eq!(
+            limit_problems(5.0, f64::INFINITY, false).len(),
+            1
+        );
+    }
+}
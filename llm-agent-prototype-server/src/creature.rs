@@ -1 +1,13 @@
+pub(super) mod affect;
+pub(super) mod definition;
 pub(super) mod my_creature;
+pub(super) mod needs;
+pub(super) mod personality;
+pub(super) mod policy;
+pub(super) mod prompt;
+pub(super) mod registry;
+pub(super) mod relationship;
+pub(super) mod retrieval;
+pub(super) mod sequence;
+pub(super) mod session;
+pub(super) mod storage;
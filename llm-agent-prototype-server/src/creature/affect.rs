@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::creature::definition::ActionChannel;
+
+/// Name of the nudges in the reaction function arguments.
+pub(crate) const NUDGES_KEY: &str = "emotion_nudges";
+
+/// Dynamics of the emotion intensities a creature keeps between turns.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct AffectSettings {
+    /// Channel whose values are the emotions.
+    #[serde(default = "default_channel")]
+    pub(crate) channel: String,
+    /// Intensity each emotion settles at when nothing happens, zero when
+    /// omitted.
+    #[serde(default)]
+    pub(crate) baseline: BTreeMap<String, f64>,
+    /// Seconds for the distance to the baseline to halve.
+    #[serde(default = "default_half_life_seconds")]
+    pub(crate) half_life_seconds: f64,
+    /// Half lives of particular emotions, e.g. anger that lingers.
+    #[serde(default)]
+    pub(crate) half_lives: BTreeMap<String, f64>,
+    /// Share of a nudge applied in one turn.
+    #[serde(default = "default_gain")]
+    pub(crate) gain: f64,
+    /// Intensity the strongest emotion needs to show instead of the
+    /// channel fallback.
+    #[serde(default = "default_threshold")]
+    pub(crate) threshold: f64,
+}
+
+fn default_channel() -> String {
+    "emotion".to_string()
+}
+
+fn default_half_life_seconds() -> f64 {
+    120.0
+}
+
+fn default_gain() -> f64 {
+    0.5
+}
+
+fn default_threshold() -> f64 {
+    0.2
+}
+
+/// Emotion intensities in `[0, 1]` that move slowly over turns.
+#[derive(Debug, Clone)]
+pub(crate) struct Affect {
+    pub(crate) intensities: BTreeMap<String, f64>,
+    updated_at: Instant,
+}
+
+impl AffectSettings {
+    pub(crate) fn schema(
+        &self,
+        channel: &ActionChannel,
+    ) -> Value {
+        let mut properties = serde_json::Map::new();
+        for value in &channel.values {
+            properties.insert(
+                value.name.clone(),
+                json!({
+                    "type": "number",
+                    "minimum": -1,
+                    "maximum": 1,
+                }),
+            );
+        }
+
+        json!({
+            "type": "object",
+            "description": format!("How much this turn pushes each {} up or down. Omit the ones it does not affect.", self.channel),
+            "properties": properties,
+        })
+    }
+
+    /// Reads the nudges of the LLM, falling back to a full push of the
+    /// emotion it picked.
+    pub(crate) fn parse_nudges(
+        &self,
+        value: Option<&Value>,
+        channel: &ActionChannel,
+        picked: Option<&String>,
+    ) -> BTreeMap<String, f64> {
+        let mut nudges = BTreeMap::new();
+
+        if let Some(object) = value.and_then(|value| value.as_object()) {
+            for (name, nudge) in object {
+                let Some(nudge) = nudge.as_f64() else {
+                    continue;
+                };
+                if channel
+                    .values
+                    .iter()
+                    .any(|value| &value.name == name)
+                {
+                    nudges.insert(name.clone(), nudge.clamp(-1.0, 1.0));
+                }
+            }
+        }
+
+        if nudges.is_empty() {
+            if let Some(picked) = picked {
+                nudges.insert(picked.clone(), 1.0);
+            }
+        }
+
+        nudges
+    }
+}
+
+impl Affect {
+    pub(crate) fn new(settings: &AffectSettings) -> Self {
+        Self {
+            intensities: settings.baseline.clone(),
+            updated_at: Instant::now(),
+        }
+    }
+
+    /// Moves every intensity toward the baseline for the time passed.
+    pub(crate) fn decay(
+        &mut self,
+        settings: &AffectSettings,
+    ) {
+        let now = Instant::now();
+        let elapsed = now
+            .duration_since(self.updated_at)
+            .as_secs_f64();
+        self.updated_at = now;
+
+        for (name, intensity) in self.intensities.iter_mut() {
+            let half_life = settings
+                .half_lives
+                .get(name)
+                .copied()
+                .unwrap_or(settings.half_life_seconds);
+            let retained = 0.5_f64.powf(elapsed / half_life);
+            let baseline = settings
+                .baseline
+                .get(name)
+                .copied()
+                .unwrap_or_default();
+            *intensity = baseline + (*intensity - baseline) * retained;
+        }
+    }
+
+    pub(crate) fn nudge(
+        &mut self,
+        settings: &AffectSettings,
+        nudges: &BTreeMap<String, f64>,
+    ) {
+        self.decay(settings);
+
+        for (name, nudge) in nudges {
+            let intensity = self
+                .intensities
+                .entry(name.clone())
+                .or_default();
+            *intensity = (*intensity + settings.gain * nudge).clamp(0.0, 1.0);
+        }
+
+        tracing::debug!("Updated affect: {:?}", self.intensities);
+    }
+
+    /// Strongest emotion among the values of the channel, or its fallback
+    /// when none is strong enough.
+    pub(crate) fn dominant(
+        &self,
+        settings: &AffectSettings,
+        channel: &ActionChannel,
+    ) -> Option<String> {
+        let strongest = channel
+            .values
+            .iter()
+            .filter(|value| channel.fallback.as_ref() != Some(&value.name))
+            .filter_map(|value| {
+                self.intensities
+                    .get(&value.name)
+                    .map(|intensity| (&value.name, *intensity))
+            })
+            .max_by(|(_, left), (_, right)| left.total_cmp(right));
+
+        match strongest {
+            | Some((name, intensity)) if intensity >= settings.threshold => {
+                Some(name.clone())
+            },
+            | _ => channel.fallback.clone(),
+        }
+    }
+
+    /// Intensities of the values of the channel, zero for untouched ones.
+    pub(crate) fn snapshot(
+        &self,
+        channel: &ActionChannel,
+    ) -> BTreeMap<String, f64> {
+        channel
+            .values
+            .iter()
+            .map(|value| {
+                (
+                    value.name.clone(),
+                    self.intensities
+                        .get(&value.name)
+                        .copied()
+                        .unwrap_or_default(),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::creature::definition::tests::definition;
+    use crate::creature::definition::CreatureDefinition;
+
+    fn affect_definition() -> CreatureDefinition {
+        definition(
+            r#"
+[affect]
+half_life_seconds = 10
+half_lives = { EMOTION_SAD = 20 }
+baseline = { EMOTION_HAPPY = 0.2 }
+threshold = 0.3
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn decay_halves_the_distance_to_the_baseline() {
+        let definition = affect_definition();
+        let settings = definition
+            .affect
+            .as_ref()
+            .unwrap();
+        let mut affect = Affect::new(settings);
+        affect
+            .intensities
+            .insert("EMOTION_HAPPY".to_string(), 1.0);
+        affect
+            .intensities
+            .insert("EMOTION_SAD".to_string(), 1.0);
+        affect.updated_at = Instant::now()
+            .checked_sub(Duration::from_secs(20))
+            .unwrap();
+
+        affect.decay(settings);
+
+        let happy = affect.intensities["EMOTION_HAPPY"];
+        let sad = affect.intensities["EMOTION_SAD"];
+        assert!((happy - 0.4).abs() < 1e-3, "{}", happy);
+        assert!((sad - 0.5).abs() < 1e-3, "{}", sad);
+    }
+
+    #[test]
+    fn decay_settles_at_the_baseline() {
+        let definition = affect_definition();
+        let settings = definition
+            .affect
+            .as_ref()
+            .unwrap();
+        let mut affect = Affect::new(settings);
+        affect
+            .intensities
+            .insert("EMOTION_HAPPY".to_string(), 0.0);
+        affect.updated_at = Instant::now()
+            .checked_sub(Duration::from_secs(600))
+            .unwrap();
+
+        affect.decay(settings);
+
+        assert!((affect.intensities["EMOTION_HAPPY"] - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dominant_needs_the_threshold() {
+        let definition = affect_definition();
+        let settings = definition
+            .affect
+            .as_ref()
+            .unwrap();
+        let channel = definition
+            .channel("emotion")
+            .unwrap();
+        let mut affect = Affect::new(settings);
+
+        assert_eq!(
+            affect
+                .dominant(settings, channel)
+                .as_deref(),
+            Some("EMOTION_NEUTRAL")
+        );
+
+        affect.nudge(
+            settings,
+            &BTreeMap::from([("EMOTION_SAD".to_string(), 0.4)]),
+        );
+        assert_eq!(
+            affect
+                .dominant(settings, channel)
+                .as_deref(),
+            Some("EMOTION_NEUTRAL")
+        );
+
+        affect.nudge(
+            settings,
+            &BTreeMap::from([("EMOTION_SAD".to_string(), 0.4)]),
+        );
+        assert_eq!(
+            affect
+                .dominant(settings, channel)
+                .as_deref(),
+            Some("EMOTION_SAD")
+        );
+    }
+
+    #[test]
+    fn dominant_ignores_the_fallback() {
+        let definition = affect_definition();
+        let settings = definition
+            .affect
+            .as_ref()
+            .unwrap();
+        let channel = definition
+            .channel("emotion")
+            .unwrap();
+        let mut affect = Affect::new(settings);
+
+        affect.nudge(
+            settings,
+            &BTreeMap::from([
+                ("EMOTION_NEUTRAL".to_string(), 1.0),
+                ("EMOTION_HAPPY".to_string(), 0.4),
+            ]),
+        );
+
+        assert_eq!(
+            affect
+                .dominant(settings, channel)
+                .as_deref(),
+            Some("EMOTION_HAPPY")
+        );
+    }
+}
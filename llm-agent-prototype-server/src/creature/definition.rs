@@ -0,0 +1,1004 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use crate::chat_gpt_api::specification::{Function, Model};
+use crate::config::CreaturesConfig;
+use crate::creature::affect::{AffectSettings, NUDGES_KEY};
+use crate::creature::needs::NeedsSettings;
+use crate::creature::personality::Personality;
+use crate::creature::policy::PolicySettings;
+use crate::creature::prompt::{self, DEFAULT_TEMPLATE};
+use crate::creature::relationship::RelationshipSettings;
+use crate::creature::retrieval::RetrievalSettings;
+use crate::creature::sequence::{SequenceSettings, TimedAction, SEQUENCE_KEY};
+
+/// Name of the function the LLM calls to report its reaction.
+pub(crate) const REACTION_FUNCTION_NAME: &str = "reaction_generator";
+
+/// Declares who a creature is and what it can do and feel, loaded from a
+/// TOML or JSON file.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct CreatureDefinition {
+    /// Defaults to the file name without extension.
+    #[serde(default)]
+    pub(crate) id: String,
+    /// Name of the creature in prompts, the id when omitted.
+    #[serde(default)]
+    pub(crate) name: Option<String>,
+    pub(crate) prompt: String,
+    /// System message template, see `prompt::PromptContext` for the
+    /// variables. The default one when omitted.
+    #[serde(default)]
+    pub(crate) template: Option<String>,
+    /// Persona rendered after the prompt that also tunes the dynamics.
+    #[serde(default)]
+    pub(crate) personality: Option<Personality>,
+    #[serde(deserialize_with = "deserialize_model")]
+    pub(crate) model: Model,
+    pub(crate) memory: MemorySettings,
+    /// Lets the creature act on its own while nobody talks to it.
+    #[serde(default)]
+    pub(crate) idle: Option<IdleSettings>,
+    pub(crate) channels: Vec<ActionChannel>,
+    #[serde(default)]
+    pub(crate) properties: Vec<NumericProperty>,
+    /// Lets the creature play several timed actions in one turn.
+    #[serde(default)]
+    pub(crate) sequence: Option<SequenceSettings>,
+    /// Keeps emotion intensities between turns instead of picking an
+    /// emotion afresh.
+    #[serde(default)]
+    pub(crate) affect: Option<AffectSettings>,
+    /// Keeps a numeric property per author on the server instead of taking
+    /// the value of the LLM.
+    #[serde(default)]
+    pub(crate) relationship: Option<RelationshipSettings>,
+    /// Rules the reactions of the LLM must follow.
+    #[serde(default)]
+    pub(crate) policy: PolicySettings,
+    /// Drives that change over time and with what happens.
+    #[serde(default)]
+    pub(crate) needs: Option<NeedsSettings>,
+}
+
+/// Filled from the server configuration where omitted.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct MemorySettings {
+    /// Number of recent messages sent to the LLM.
+    pub(crate) context_size: usize,
+    /// Number of related memories sent to the LLM.
+    pub(crate) search_limit: u64,
+    /// Own Qdrant collection of the creature. When omitted the creature
+    /// shares the default collection under a namespace of its id.
+    #[serde(default)]
+    pub(crate) collection: Option<String>,
+    #[serde(default)]
+    pub(crate) retrieval: RetrievalSettings,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct IdleSettings {
+    /// Seconds of silence on a stream before the creature acts.
+    #[serde(default)]
+    pub(crate) after_silence_seconds: Option<u64>,
+    /// Interval of actions regardless of the conversation.
+    #[serde(default)]
+    pub(crate) every_seconds: Option<u64>,
+    /// System message given to the LLM in place of a user message.
+    #[serde(default = "default_idle_cue")]
+    pub(crate) cue: String,
+    /// Idle actions in a row after which the creature waits for someone to
+    /// talk to it again, bounding the LLM calls of an abandoned stream.
+    #[serde(default = "default_max_unanswered")]
+    pub(crate) max_unanswered: u32,
+}
+
+fn default_max_unanswered() -> u32 {
+    5
+}
+
+fn default_idle_cue() -> String {
+    "Nobody has talked to you for a while. Do something on your own, like yawning, wandering or calling to the player.".to_string()
+}
+
+/// Fills the settings a definition omits from the server configuration.
+fn fill_defaults(
+    value: &mut serde_json::Value,
+    defaults: &CreaturesConfig,
+) -> Result<()> {
+    let definition = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Creature definition is not a table"))?;
+    definition
+        .entry("model")
+        .or_insert_with(|| json!(defaults.model));
+
+    let memory = definition
+        .entry("memory")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("Memory settings are not a table"))?;
+    memory
+        .entry("context_size")
+        .or_insert_with(|| json!(defaults.context_size));
+    memory
+        .entry("search_limit")
+        .or_insert_with(|| json!(defaults.search_limit));
+
+    Ok(())
+}
+
+fn deserialize_model<'de, D>(deserializer: D) -> Result<Model, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    Model::parse_to_model(&name).map_err(serde::de::Error::custom)
+}
+
+/// A slot of the reaction that takes exactly one of its values per turn,
+/// e.g. emotion, motion or cry.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ActionChannel {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    pub(crate) values: Vec<ActionValue>,
+    /// Value used in place of one outside `values`.
+    #[serde(default)]
+    pub(crate) fallback: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ActionValue {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+}
+
+/// A number the LLM reports in a fixed range, e.g. friendliness.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct NumericProperty {
+    pub(crate) name: String,
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    pub(crate) minimum: f64,
+    pub(crate) maximum: f64,
+}
+
+/// Action values a client can play, keyed by channel.
+pub(crate) type Vocabulary = HashMap<String, HashSet<String>>;
+
+/// Reaction of the LLM validated against a definition.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reaction {
+    pub(crate) actions: BTreeMap<String, String>,
+    pub(crate) properties: BTreeMap<String, f64>,
+    pub(crate) sequence: Vec<TimedAction>,
+    /// Pushes of the LLM on the emotion intensities.
+    pub(crate) nudges: BTreeMap<String, f64>,
+    /// Emotion intensities after this turn.
+    pub(crate) intensities: BTreeMap<String, f64>,
+    /// Change of the relationship proposed by the LLM.
+    pub(crate) relationship_delta: f64,
+    /// Needs after this turn when the definition exposes them.
+    pub(crate) needs: BTreeMap<String, f64>,
+}
+
+impl CreatureDefinition {
+    #[tracing::instrument(
+        name = "creature.definition.load",
+        err,
+        skip(path, defaults),
+        fields(path = %path.as_ref().display())
+    )]
+    pub(crate) fn load(
+        path: impl AsRef<Path>,
+        defaults: &CreaturesConfig,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| {
+            tracing::error!(
+                "Failed to read creature definition: {:?}",
+                error
+            );
+            error
+        })?;
+
+        let value: serde_json::Value = match path
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            | Some("json") => serde_json::from_str(&text)?,
+            | Some("toml") => toml::from_str(&text)?,
+            | _ => {
+                return Err(anyhow!(
+                    "Unsupported creature definition format: {}",
+                    path.display()
+                ))
+            },
+        };
+
+        Self::from_value(
+            value,
+            path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default(),
+            defaults,
+        )
+    }
+
+    /// Builds and validates the definition of a parsed file, with the id
+    /// when the file names none.
+    fn from_value(
+        mut value: serde_json::Value,
+        id: &str,
+        defaults: &CreaturesConfig,
+    ) -> Result<Self> {
+        fill_defaults(&mut value, defaults)?;
+        let mut definition: CreatureDefinition = serde_json::from_value(value)?;
+
+        if definition.id.is_empty() {
+            definition.id = id.to_string();
+        }
+
+        if let Some(personality) = definition.personality.clone() {
+            personality.validate(&definition)?;
+            personality.shape(&mut definition);
+        }
+
+        definition.validate()?;
+
+        tracing::info!(
+            "Loaded creature {} with {} channels and {} properties",
+            definition.id,
+            definition.channels.len(),
+            definition.properties.len()
+        );
+
+        Ok(definition)
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.id.is_empty() {
+            return Err(anyhow!("Creature id is empty"));
+        }
+        prompt::validate(self.template()).map_err(|error| {
+            anyhow!(
+                "Invalid prompt template of creature {}: {}",
+                self.id,
+                error
+            )
+        })?;
+        if self.memory.context_size == 0 || self.memory.search_limit == 0 {
+            return Err(anyhow!(
+                "Memory sizes of creature {} must be positive",
+                self.id
+            ));
+        }
+        self.memory
+            .retrieval
+            .validate()
+            .map_err(|error| {
+                anyhow!(
+                    "Invalid memory retrieval of creature {}: {}",
+                    self.id,
+                    error
+                )
+            })?;
+        if let Some(idle) = &self.idle {
+            if idle
+                .after_silence_seconds
+                .is_none()
+                && idle.every_seconds.is_none()
+            {
+                return Err(anyhow!(
+                    "Idle settings of creature {} have no timer",
+                    self.id
+                ));
+            }
+            if idle.after_silence_seconds == Some(0)
+                || idle.every_seconds == Some(0)
+                || idle.max_unanswered == 0
+            {
+                return Err(anyhow!(
+                    "Idle timers and max_unanswered of creature {} must be \
+                     positive",
+                    self.id
+                ));
+            }
+        }
+        if self.channels.is_empty() {
+            return Err(anyhow!(
+                "Creature definition has no action channels"
+            ));
+        }
+
+        if let Some(sequence) = &self.sequence {
+            if sequence.max_length == 0
+                || sequence.max_total_duration_ms == 0
+                || sequence.max_loop_count == 0
+                || sequence.loop_duration_ms == 0
+            {
+                return Err(anyhow!(
+                    "Sequence limits of creature {} must be positive",
+                    self.id
+                ));
+            }
+        }
+
+        if let Some(affect) = &self.affect {
+            self.validate_affect(affect)?;
+        }
+        if let Some(relationship) = &self.relationship {
+            self.validate_relationship(relationship)?;
+        }
+        self.policy.validate(self)?;
+        if let Some(needs) = &self.needs {
+            needs.validate(self)?;
+        }
+
+        let delta_key = self
+            .relationship
+            .as_ref()
+            .map(|relationship| relationship.delta_key());
+        let mut names = HashSet::from([
+            SEQUENCE_KEY,
+            NUDGES_KEY,
+        ]);
+        if let Some(delta_key) = &delta_key {
+            names.insert(delta_key.as_str());
+        }
+        for channel in &self.channels {
+            if !names.insert(channel.name.as_str()) {
+                return Err(anyhow!(
+                    "Duplicated name: {}",
+                    channel.name
+                ));
+            }
+            if channel.values.is_empty() {
+                return Err(anyhow!(
+                    "Channel {} has no values",
+                    channel.name
+                ));
+            }
+
+            let mut values = HashSet::new();
+            for value in &channel.values {
+                if !values.insert(value.name.as_str()) {
+                    return Err(anyhow!(
+                        "Duplicated value {} in channel {}",
+                        value.name,
+                        channel.name
+                    ));
+                }
+            }
+
+            if let Some(fallback) = &channel.fallback {
+                if !values.contains(fallback.as_str()) {
+                    return Err(anyhow!(
+                        "Fallback {} is not a value of channel {}",
+                        fallback,
+                        channel.name
+                    ));
+                }
+            }
+        }
+
+        for property in &self.properties {
+            if !names.insert(property.name.as_str()) {
+                return Err(anyhow!(
+                    "Duplicated name: {}",
+                    property.name
+                ));
+            }
+            if property
+                .minimum
+                .partial_cmp(&property.maximum)
+                != Some(Ordering::Less)
+            {
+                return Err(anyhow!(
+                    "Property {} must have minimum below maximum",
+                    property.name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_affect(
+        &self,
+        affect: &AffectSettings,
+    ) -> Result<()> {
+        let channel = self
+            .channel(&affect.channel)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Affect channel {} of creature {} is not defined",
+                    affect.channel,
+                    self.id
+                )
+            })?;
+        if affect.half_life_seconds <= 0.0
+            || affect.gain <= 0.0
+            || affect
+                .half_lives
+                .values()
+                .any(|half_life| *half_life <= 0.0)
+        {
+            return Err(anyhow!(
+                "Affect half life and gain of creature {} must be positive",
+                self.id
+            ));
+        }
+        if !(0.0..=1.0).contains(&affect.threshold) {
+            return Err(anyhow!(
+                "Affect threshold of creature {} must be in [0, 1]",
+                self.id
+            ));
+        }
+        for name in affect.half_lives.keys() {
+            if !channel
+                .values
+                .iter()
+                .any(|value| &value.name == name)
+            {
+                return Err(anyhow!(
+                    "Half life {} is not a value of channel {}",
+                    name,
+                    channel.name
+                ));
+            }
+        }
+        for (name, intensity) in &affect.baseline {
+            if !channel
+                .values
+                .iter()
+                .any(|value| &value.name == name)
+            {
+                return Err(anyhow!(
+                    "Baseline {} is not a value of channel {}",
+                    name,
+                    channel.name
+                ));
+            }
+            if !(0.0..=1.0).contains(intensity) {
+                return Err(anyhow!(
+                    "Baseline {} must be between 0 and 1",
+                    name
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_relationship(
+        &self,
+        relationship: &RelationshipSettings,
+    ) -> Result<()> {
+        let property = self
+            .property(&relationship.property)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Relationship property {} of creature {} is not defined",
+                    relationship.property,
+                    self.id
+                )
+            })?;
+        if relationship.max_delta <= 0.0 {
+            return Err(anyhow!(
+                "Relationship max delta of creature {} must be positive",
+                self.id
+            ));
+        }
+        if relationship.smoothing <= 0.0 || relationship.smoothing > 1.0 {
+            return Err(anyhow!(
+                "Relationship smoothing of creature {} must be in (0, 1]",
+                self.id
+            ));
+        }
+        if !(property.minimum..=property.maximum)
+            .contains(&relationship.initial)
+        {
+            return Err(anyhow!(
+                "Initial {} of creature {} is out of range",
+                property.name,
+                self.id
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .unwrap_or(&self.id)
+    }
+
+    pub(crate) fn template(&self) -> &str {
+        self.template
+            .as_deref()
+            .unwrap_or(DEFAULT_TEMPLATE)
+    }
+
+    pub(crate) fn property(
+        &self,
+        name: &str,
+    ) -> Option<&NumericProperty> {
+        self.properties
+            .iter()
+            .find(|property| property.name == name)
+    }
+
+    pub(crate) fn channel(
+        &self,
+        name: &str,
+    ) -> Option<&ActionChannel> {
+        self.channels
+            .iter()
+            .find(|channel| channel.name == name)
+    }
+
+    /// Narrows the channels to the values a client declared it can play.
+    /// Channels the client did not mention keep all of their values.
+    pub(crate) fn restrict(
+        &self,
+        vocabulary: &Vocabulary,
+    ) -> Result<CreatureDefinition> {
+        let (restricted, mismatches) = self.intersect(vocabulary);
+        match mismatches.first() {
+            | Some(mismatch) => Err(anyhow!("{}", mismatch)),
+            | None => Ok(restricted),
+        }
+    }
+
+    /// Narrows the channels like `restrict` but skips what the definition
+    /// does not know, reporting it instead, so that a vocabulary accepted
+    /// on handshake keeps working after the definition is edited. Channels
+    /// left without any declared value keep all of their values.
+    pub(crate) fn intersect(
+        &self,
+        vocabulary: &Vocabulary,
+    ) -> (CreatureDefinition, Vec<String>) {
+        let mut mismatches = Vec::new();
+        for name in vocabulary.keys() {
+            if !self
+                .channels
+                .iter()
+                .any(|channel| &channel.name == name)
+            {
+                mismatches.push(format!("Unknown channel: {}", name));
+            }
+        }
+
+        let mut restricted = self.clone();
+        for channel in &mut restricted.channels {
+            let Some(declared) = vocabulary.get(&channel.name) else {
+                continue;
+            };
+
+            for unknown in declared
+                .iter()
+                .filter(|declared| {
+                    !channel
+                        .values
+                        .iter()
+                        .any(|value| &&value.name == declared)
+                })
+            {
+                mismatches.push(format!(
+                    "Unknown value {} in channel {}",
+                    unknown, channel.name
+                ));
+            }
+
+            if !channel
+                .values
+                .iter()
+                .any(|value| declared.contains(&value.name))
+            {
+                mismatches.push(format!(
+                    "No values declared for channel {}",
+                    channel.name
+                ));
+                continue;
+            }
+            channel
+                .values
+                .retain(|value| declared.contains(&value.name));
+
+            // Keep remapping to a value the client can play.
+            if let Some(fallback) = &channel.fallback {
+                if !declared.contains(fallback) {
+                    channel.fallback = Some(channel.values[0].name.clone());
+                }
+            }
+        }
+
+        (restricted, mismatches)
+    }
+
+    /// Builds the function the LLM calls to report its reaction.
+    pub(crate) fn reaction_function(&self) -> Function {
+        let mut properties = serde_json::Map::new();
+        let mut required = Vec::new();
+
+        for channel in &self.channels {
+            let mut schema = json!({
+                "type": "string",
+                "enum": channel
+                    .values
+                    .iter()
+                    .map(|value| value.name.clone())
+                    .collect::<Vec<_>>(),
+            });
+            if let Some(description) = channel.describe() {
+                schema["description"] = json!(description);
+            }
+            properties.insert(channel.name.clone(), schema);
+            required.push(channel.name.clone());
+        }
+
+        for property in &self.properties {
+            if let Some(relationship) = self.owned_by_relationship(property) {
+                properties.insert(
+                    relationship.delta_key(),
+                    relationship.schema(property),
+                );
+                required.push(relationship.delta_key());
+                continue;
+            }
+
+            let mut schema = json!({
+                "type": "number",
+                "minimum": property.minimum,
+                "maximum": property.maximum,
+            });
+            if let Some(description) = &property.description {
+                schema["description"] = json!(description);
+            }
+            properties.insert(property.name.clone(), schema);
+            required.push(property.name.clone());
+        }
+
+        if let Some(sequence) = &self.sequence {
+            properties.insert(
+                SEQUENCE_KEY.to_string(),
+                sequence.schema(&self.channels),
+            );
+        }
+
+        if let Some(affect) = &self.affect {
+            if let Some(channel) = self.channel(&affect.channel) {
+                properties.insert(
+                    NUDGES_KEY.to_string(),
+                    affect.schema(channel),
+                );
+            }
+        }
+
+        Function::new(
+            REACTION_FUNCTION_NAME.to_string(),
+            Some("Generate your reaction as character of creature from conversations.".to_string()),
+            json!({
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            })
+            .to_string(),
+        )
+    }
+
+    /// Parses the arguments of a reaction function call.
+    pub(crate) fn parse_reaction(
+        &self,
+        arguments: &str,
+    ) -> Result<Reaction> {
+        let arguments = serde_json::from_str::<
+            serde_json::Map<String, serde_json::Value>,
+        >(arguments)?;
+
+        let mut reaction = Reaction::default();
+
+        for channel in &self.channels {
+            let mut value = arguments
+                .get(&channel.name)
+                .and_then(|value| value.as_str())
+                .ok_or_else(|| anyhow!("Missing {}", channel.name))?;
+            if !channel
+                .values
+                .iter()
+                .any(|allowed| allowed.name == value)
+            {
+                let fallback = channel
+                    .fallback
+                    .as_deref()
+                    .ok_or_else(|| {
+                        anyhow!("Invalid {}: {:?}", channel.name, value)
+                    })?;
+                tracing::warn!(
+                    "Remapped invalid {} {:?} to {}",
+                    channel.name,
+                    value,
+                    fallback
+                );
+                value = fallback;
+            }
+            reaction
+                .actions
+                .insert(channel.name.clone(), value.to_string());
+        }
+
+        for property in &self.properties {
+            if let Some(relationship) = self.owned_by_relationship(property) {
+                // The server fills the value from its own record.
+                reaction.relationship_delta = arguments
+                    .get(&relationship.delta_key())
+                    .and_then(|value| value.as_f64())
+                    .unwrap_or_else(|| {
+                        tracing::warn!(
+                            "Missing {}, assumed no change",
+                            relationship.delta_key()
+                        );
+                        0.0
+                    });
+                continue;
+            }
+
+            let value = arguments
+                .get(&property.name)
+                .and_then(|value| value.as_f64())
+                .ok_or_else(|| anyhow!("Missing {}", property.name))?;
+            // The LLM does not always respect the range.
+            reaction.properties.insert(
+                property.name.clone(),
+                value.clamp(property.minimum, property.maximum),
+            );
+        }
+
+        if let Some(sequence) = &self.sequence {
+            reaction.sequence = sequence.parse(
+                arguments.get(SEQUENCE_KEY),
+                &self.channels,
+            );
+        }
+
+        if let Some(affect) = &self.affect {
+            if let Some(channel) = self.channel(&affect.channel) {
+                reaction.nudges = affect.parse_nudges(
+                    arguments.get(NUDGES_KEY),
+                    channel,
+                    reaction
+                        .actions
+                        .get(&channel.name),
+                );
+            }
+        }
+
+        Ok(reaction)
+    }
+
+    fn owned_by_relationship(
+        &self,
+        property: &NumericProperty,
+    ) -> Option<&RelationshipSettings> {
+        self.relationship
+            .as_ref()
+            .filter(|relationship| relationship.property == property.name)
+    }
+}
+
+impl ActionChannel {
+    /// Channel description followed by the meaning of each value, since
+    /// JSON schema enums cannot describe their members.
+    fn describe(&self) -> Option<String> {
+        let mut lines = Vec::new();
+        if let Some(description) = &self.description {
+            lines.push(description.clone());
+        }
+        for value in &self.values {
+            if let Some(description) = &value.description {
+                lines.push(format!(
+                    "{}: {}",
+                    value.name, description
+                ));
+            }
+        }
+
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Definition with the tables in `text` and the channels and properties
+    /// shared by the tests.
+    pub(crate) fn definition(text: &str) -> Result<CreatureDefinition> {
+        let text = format!(
+            r#"
+prompt = "You are a test creature."
+{}
+
+[[channels]]
+name = "emotion"
+fallback = "EMOTION_NEUTRAL"
+values = [
+    {{ name = "EMOTION_NEUTRAL" }},
+    {{ name = "EMOTION_HAPPY" }},
+    {{ name = "EMOTION_SAD" }},
+]
+
+[[channels]]
+name = "motion"
+fallback = "MOTION_NEUTRAL"
+values = [
+    {{ name = "MOTION_NEUTRAL" }},
+    {{ name = "MOTION_JUMP" }},
+    {{ name = "MOTION_ATTACK" }},
+    {{ name = "MOTION_DIE" }},
+    {{ name = "MOTION_EATING" }},
+]
+
+[[properties]]
+name = "friendliness"
+minimum = -1.0
+maximum = 1.0
+"#,
+            text
+        );
+
+        CreatureDefinition::from_value(
+            toml::from_str(&text)?,
+            "test",
+            &CreaturesConfig::default(),
+        )
+    }
+
+    #[test]
+    fn parse_reaction_remaps_invalid_values_to_the_fallback() {
+        let definition = definition("").unwrap();
+
+        let reaction = definition
+            .parse_reaction(
+                r#"{"emotion": "EMOTION_BORED", "motion": "MOTION_JUMP", "friendliness": 0.5}"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            reaction.actions["emotion"],
+            "EMOTION_NEUTRAL"
+        );
+        assert_eq!(
+            reaction.actions["motion"],
+            "MOTION_JUMP"
+        );
+    }
+
+    #[test]
+    fn parse_reaction_clamps_properties_to_their_range() {
+        let definition = definition("").unwrap();
+
+        let reaction = definition
+            .parse_reaction(
+                r#"{"emotion": "EMOTION_HAPPY", "motion": "MOTION_JUMP", "friendliness": 3.0}"#,
+            )
+            .unwrap();
+
+        assert_eq!(reaction.properties["friendliness"], 1.0);
+    }
+
+    #[test]
+    fn intersect_keeps_declared_values_and_reports_unknown_ones() {
+        let definition = definition("").unwrap();
+        let vocabulary = Vocabulary::from([
+            (
+                "motion".to_string(),
+                HashSet::from([
+                    "MOTION_JUMP".to_string(),
+                    "MOTION_FLY".to_string(),
+                ]),
+            ),
+            (
+                "cry".to_string(),
+                HashSet::from(["CRY_NONE".to_string()]),
+            ),
+        ]);
+
+        let (restricted, mismatches) = definition.intersect(&vocabulary);
+
+        let motion = restricted
+            .channel("motion")
+            .unwrap();
+        assert_eq!(motion.values.len(), 1);
+        assert_eq!(motion.values[0].name, "MOTION_JUMP");
+        // The fallback is remapped to a value the client can play.
+        assert_eq!(
+            motion.fallback.as_deref(),
+            Some("MOTION_JUMP")
+        );
+        assert_eq!(
+            restricted
+                .channel("emotion")
+                .unwrap()
+                .values
+                .len(),
+            3
+        );
+        assert_eq!(mismatches.len(), 2);
+        assert!(definition
+            .restrict(&vocabulary)
+            .is_err());
+    }
+
+    #[test]
+    fn intersect_keeps_channels_without_declared_values() {
+        let definition = definition("").unwrap();
+        let vocabulary = Vocabulary::from([(
+            "motion".to_string(),
+            HashSet::from(["MOTION_FLY".to_string()]),
+        )]);
+
+        let (restricted, mismatches) = definition.intersect(&vocabulary);
+
+        assert_eq!(
+            restricted
+                .channel("motion")
+                .unwrap()
+                .values
+                .len(),
+            5
+        );
+        assert_eq!(mismatches.len(), 2);
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let invalid = [
+            r#"
+[[channels]]
+name = "emotion"
+values = [{ name = "EMOTION_ANGRY" }]
+"#,
+            r#"
+[[channels]]
+name = "cry"
+fallback = "CRY_NONE"
+values = [{ name = "CRY_HAPPY" }]
+"#,
+            r#"
+[[channels]]
+name = "cry"
+values = [{ name = "CRY_HAPPY" }, { name = "CRY_HAPPY" }]
+"#,
+            r#"
+[[properties]]
+name = "hunger"
+minimum = 1.0
+maximum = 0.0
+"#,
+            r#"
+[idle]
+after_silence_seconds = 0
+"#,
+        ];
+
+        assert!(definition("").is_ok());
+        for text in invalid {
+            assert!(definition(text).is_err(), "{}", text);
+        }
+    }
+}
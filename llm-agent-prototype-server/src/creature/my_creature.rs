@@ -7,14 +7,25 @@ pub(crate) mod creature_rpc {
 
 use crate::chat_gpt_api::memory::Memory;
 use crate::chat_gpt_api::specification::{
-    Function, FunctionCallingSpecification, Message, Options, Role,
+    FunctionCallingSpecification, Message, Options, Role,
 };
+use crate::creature::definition::{
+    CreatureDefinition, Reaction, Vocabulary, REACTION_FUNCTION_NAME,
+};
+use crate::creature::needs::Trigger;
+use crate::creature::policy::PolicyKey;
+use crate::creature::prompt::{
+    self, EmotionContext, MemoryContext, NeedContext, PromptContext,
+};
+use crate::creature::registry::CreatureRegistry;
+use crate::creature::retrieval;
+use crate::creature::session::Session;
+use crate::rate_limit::RateLimiter;
 use crate::rpc_context::RpcContext;
-use crate::vector_db::database::{self, Record};
+use crate::vector_db::database::{self, Record, RecordKind, Visibility};
 use creature_rpc::creature_server::Creature;
+use creature_rpc::talking::Input;
 use creature_rpc::{Cry, Emotion, Motion};
-use futures::stream::StreamExt;
-use qdrant_client::qdrant::ScoredPoint;
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -24,15 +35,8 @@ use tonic::{Response, Status};
 
 #[derive(Debug)]
 pub struct MyCreature {
-    pub(crate) context: Arc<Mutex<RpcContext>>,
-}
-
-#[derive(serde::Deserialize, Debug)]
-struct StateJson {
-    emotion: String,
-    motion: String,
-    cry: String,
-    friendliness: f64,
+    pub(crate) registry: Arc<CreatureRegistry>,
+    pub(crate) rate_limiter: Arc<Mutex<RateLimiter>>,
 }
 
 #[tonic::async_trait]
@@ -47,6 +51,7 @@ impl Creature for MyCreature {
     >;
 
     // grpcurl -plaintext -d '{ "message": "おはよう!", "author": "Mochineko" }' 127.0.0.1:50051 creature.Creature/Talk
+    // grpcurl -plaintext -d '{ "event": { "kind": "feed", "actor": "Mochineko", "target": "creature", "parameters": { "food": "apple" } }, "author": "Mochineko" }' 127.0.0.1:50051 creature.Creature/Talk
     #[tracing::instrument(
         name = "creature.talk",
         err,
@@ -59,41 +64,26 @@ impl Creature for MyCreature {
     {
         tracing::info!("Request talk: {:?}", request);
 
-        let mut stream = request.into_inner();
+        // Creature addressed by metadata unless a message names another.
+        let stream_creature_id = request
+            .metadata()
+            .get(CREATURE_ID_METADATA_KEY)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.to_string());
+
+        let stream = request.into_inner();
 
         let (tx, rx) = mpsc::channel(100);
 
-        let context = self.context.clone();
-
-        tokio::spawn(async move {
-            while let Some(request) = stream.next().await {
-                let request = match request {
-                    | Ok(req) => req,
-                    | Err(e) => {
-                        tracing::error!("Failed to receive request: {:?}", e);
-                        let _ = tx.send(Err(e)).await;
-                        break;
-                    },
-                };
-                let context = context.lock().await;
-                let response = match react(context, request).await {
-                    | Ok(resp) => resp,
-                    | Err(e) => {
-                        tracing::error!("Failed to react: {:?}", e);
-                        let _ = tx.send(Err(e)).await;
-                        break;
-                    },
-                };
-                if tx
-                    .send(Ok(response))
-                    .await
-                    .is_err()
-                {
-                    tracing::error!("Failed to send response");
-                    break;
-                }
-            }
-        });
+        let session = Session::new(
+            self.registry.clone(),
+            self.rate_limiter.clone(),
+            stream_creature_id,
+            tx,
+        )
+        .await;
+
+        tokio::spawn(session.run(stream));
 
         let outgoing = ReceiverStream::new(rx);
 
@@ -108,9 +98,24 @@ impl Creature for MyCreature {
     }
 }
 
+/// Metadata key naming the creature a stream talks to.
+const CREATURE_ID_METADATA_KEY: &str = "creature-id";
+
+/// What a creature reacts to.
+#[derive(Debug)]
+pub(super) enum Stimulus {
+    Talking(creature_rpc::Talking),
+    /// Nothing happened for a while, so the creature acts on a cue.
+    Idle {
+        cue: String,
+        /// Author of the last message on the stream, whose relationship
+        /// the reaction reports.
+        last_author: Option<String>,
+    },
+}
+
 fn build_messages(
-    prompt: String,
-    memory: String,
+    system: String,
     context: Vec<Message>,
 ) -> Vec<Message> {
     let mut messages = Vec::new();
@@ -119,10 +124,7 @@ fn build_messages(
         role: Role::System
             .parse_to_string()
             .unwrap(),
-        content: Some(format!(
-            "{}\nRelated memories:\n{}",
-            prompt, memory
-        )),
+        content: Some(system),
         name: None,
         function_call: None,
     });
@@ -137,20 +139,68 @@ fn build_messages(
 #[tracing::instrument(
     name = "creature.talk_react",
     err,
-    skip(context, talking)
+    skip(context, stimulus, vocabulary)
 )]
-async fn react(
+pub(super) async fn react(
     mut context: MutexGuard<'_, RpcContext>,
-    talking: creature_rpc::Talking,
-) -> Result<creature_rpc::State, Status> {
+    stimulus: Stimulus,
+    vocabulary: Option<&Vocabulary>,
+) -> Result<(creature_rpc::State, u64), Status> {
     tracing::info!(
-        "Request react to talking: {:?}",
-        talking
+        "Request react to stimulus: {:?}",
+        stimulus
     );
 
-    let related_memories = context
+    let definition = match vocabulary {
+        | None => context.definition.clone(),
+        | Some(vocabulary) => {
+            let (definition, mismatches) = context
+                .definition
+                .intersect(vocabulary);
+            for mismatch in mismatches {
+                tracing::warn!(
+                    "Ignored declared action vocabulary: {}",
+                    mismatch
+                );
+            }
+            definition
+        },
+    };
+
+    let (author, last_author) = match &stimulus {
+        | Stimulus::Talking(talking) => (Some(talking.author.clone()), None),
+        | Stimulus::Idle {
+            last_author,
+            ..
+        } => (None, last_author.clone()),
+    };
+
+    let query = match &stimulus {
+        | Stimulus::Talking(talking) => match &talking.input {
+            | Some(Input::Message(message)) => message.clone(),
+            | Some(Input::Event(event)) => describe_event(event),
+            | None => {
+                tracing::error!("Talking has no input");
+                return Err(Status::new(
+                    tonic::Code::InvalidArgument,
+                    "Talking has no input".to_string(),
+                ));
+            },
+        },
+        | Stimulus::Idle {
+            cue,
+            ..
+        } => cue.clone(),
+    };
+    let retrieval = &definition.memory.retrieval;
+    let now = chrono::Utc::now();
+    let candidates = context
         .long_memory
-        .search(talking.message.clone(), 10, None)
+        .search(
+            query.clone(),
+            retrieval.candidates(definition.memory.search_limit),
+            Some(retrieval.filter(author.as_deref(), now)),
+        )
         .await
         .map_err(|error| {
             tracing::error!(
@@ -162,116 +212,133 @@ async fn react(
                 "Failed to search related memories".to_string(),
             )
         })?;
-    let combined_related_memories = combine_to_string(related_memories);
-
-    context
-        .context_memory
-        .add(Message {
-            role: Role::User
-                .parse_to_string()
-                .unwrap(),
-            content: Some(talking.message.clone()),
-            name: None,
-            function_call: None,
-        });
+    let memories = to_memories(retrieval.rank(
+        candidates,
+        definition.memory.search_limit as usize,
+        now,
+    ));
+
+    feel_needs(&mut context, &definition, &stimulus);
+
+    // Events and idle actions happen in the world for anyone to see, while
+    // speech and the reaction to it stay with whom it was told by.
+    let visibility = match &stimulus {
+        | Stimulus::Talking(talking) => match talking.input {
+            | Some(Input::Event(_)) => Visibility::Public,
+            | _ => Visibility::Private,
+        },
+        | Stimulus::Idle {
+            ..
+        } => Visibility::Public,
+    };
+    let occasion = describe_occasion(&stimulus, &query);
+
+    // The cue only asks for this turn, so it is not kept in the context
+    // memory shared with later turns.
+    let mut idle_cue = None;
+    match stimulus {
+        | Stimulus::Talking(talking) => {
+            let (role, content, kind) = match talking.input {
+                | Some(Input::Event(_)) => (
+                    Role::System,
+                    format!("Event: {}", query),
+                    RecordKind::Event,
+                ),
+                | _ => (
+                    Role::User,
+                    query.clone(),
+                    RecordKind::Speech,
+                ),
+            };
+
+            context
+                .context_memory
+                .add(Message {
+                    role: role
+                        .parse_to_string()
+                        .unwrap(),
+                    content: Some(content),
+                    name: None,
+                    function_call: None,
+                });
+
+            let importance = retrieval::rate_importance(&query, kind);
+            context
+                .long_memory
+                .upsert(database::Record::new(
+                    query,
+                    talking.author,
+                    kind,
+                    visibility,
+                    importance,
+                ))
+                .await
+                .map_err(|error| {
+                    tracing::error!(
+                        "Failed to upsert message to long memory: {:?}",
+                        error
+                    );
+                    Status::new(
+                        tonic::Code::Internal,
+                        "Failed to upsert to long memory".to_string(),
+                    )
+                })?;
+        },
+        | Stimulus::Idle {
+            cue,
+            ..
+        } => idle_cue = Some(cue),
+    }
 
-    context
-        .long_memory
-        .upsert(database::Record::new(
-            talking.message,
-            talking.author,
-        ))
-        .await
+    let mut prompt_context = PromptContext::now(
+        definition.name().to_string(),
+        definition.prompt.clone(),
+    );
+    prompt_context.personality = definition
+        .personality
+        .as_ref()
+        .map(|personality| personality.describe());
+    prompt_context.friendliness =
+        friendliness(&context, &definition, author.as_deref());
+    prompt_context.author = author.clone();
+    prompt_context.mood = mood(&mut context, &definition);
+    prompt_context.needs = needs(&context, &definition);
+    prompt_context.memories = memories;
+    let system = prompt::render(definition.template(), &prompt_context)
         .map_err(|error| {
             tracing::error!(
-                "Failed to upsert message to long memory: {:?}",
+                "Failed to render system prompt: {:?}",
                 error
             );
             Status::new(
                 tonic::Code::Internal,
-                "Failed to upsert to long memory".to_string(),
+                "Failed to render system prompt".to_string(),
             )
         })?;
 
     let context_memory = context.context_memory.get();
-    let messages = build_messages(
-        context.prompt.clone(),
-        combined_related_memories,
-        context_memory.clone(),
-    );
-    let functions = vec![Function::new(
-        "reaction_generator".to_string(),
-        Some("Generate your reaction as character of creature from conversations.".to_string()),
-        r#"{
-            "type": "object",
-            "properties": {
-                "emotion": {
-                    "type": "string",
-                    "enum": [
-                        "EMOTION_NEUTRAL",
-                        "EMOTION_HAPPY",
-                        "EMOTION_SAD",
-                        "EMOTION_ANGRY",
-                        "EMOTION_FEARFUL",
-                        "EMOTION_DISGUSTED",
-                        "EMOTION_SURPRISED"
-                    ]
-                },
-                "motion": {
-                    "type": "string",
-                    "enum": [
-                        "MOTION_NEUTRAL",
-                        "MOTION_HAPPY",
-                        "MOTION_NO",
-                        "MOTION_JUMP",
-                        "MOTION_DIE",
-                        "MOTION_RUN",
-                        "MOTION_WALK",
-                        "MOTION_FLYING",
-                        "MOTION_ATTACK",
-                        "MOTION_EATING"
-                    ]
-                },
-                "cry": {
-                    "type": "string",
-                    "enum": [
-                        "CRY_NONE",
-                        "CRY_HAPPY",
-                        "CRY_SAD",
-                        "CRY_ANGRY",
-                        "CRY_FEARFUL",
-                        "CRY_DISGUSTED",
-                        "CRY_SURPRISED",
-                        "CRY_SPOILED",
-                        "CRY_CRY"
-                    ]
-                },
-                "friendliness" : {
-                    "type": "number",
-                    "description": "Friendliness of creature that changes slowly by user interaction.",
-                    "minimum": -1,
-                    "maximum": 1
-                }
-            },
-            "required": [
-                "emotion",
-                "motion",
-                "cry",
-                "friendliness"
-            ]
-        }"#
-        .to_string(),
-    )];
+    let mut messages = build_messages(system, context_memory.clone());
+    if let Some(cue) = idle_cue {
+        messages.push(Message {
+            role: Role::System
+                .parse_to_string()
+                .unwrap(),
+            content: Some(cue),
+            name: None,
+            function_call: None,
+        });
+    }
+    let functions = vec![definition.reaction_function()];
 
     let options: Options = Options {
-        model: context
+        model: definition
             .model
             .parse_to_string()
             .unwrap(),
         messages,
         functions: Some(functions),
         function_call: Some(FunctionCallingSpecification::Name(
-            "reaction_generator".to_string(),
+            REACTION_FUNCTION_NAME.to_string(),
         )),
         temperature: None,
         top_p: None,
@@ -337,80 +404,505 @@ async fn react(
                                 .clone(),
                         });
 
-                    let reaction = serde_json::from_str::<StateJson>(
-                        &function_call.arguments,
-                    )
-                    .map_err(|error| {
-                        tracing::error!(
-                            "Failed to parse function calling arguments: {:?}",
-                            error
-                        );
-                        Status::new(
-                            tonic::Code::Internal,
-                            "Failed to parse function calling arguments"
-                                .to_string(),
-                        )
-                    })?;
-
-                    let emotion = Emotion::from_str_name(&reaction.emotion)
-                        .ok_or_else(|| {
+                    let mut reaction = definition
+                        .parse_reaction(&function_call.arguments)
+                        .map_err(|error| {
                             tracing::error!(
-                                "Invalid emotion: {:?}",
-                                reaction.emotion
+                                "Failed to parse function calling arguments: {:?}",
+                                error
                             );
                             Status::new(
                                 tonic::Code::Internal,
-                                "Failed to parse emotion".to_string(),
+                                "Failed to parse function calling arguments"
+                                    .to_string(),
                             )
                         })?;
-
-                    let motion = Motion::from_str_name(&reaction.motion)
-                        .ok_or_else(|| {
-                            tracing::error!(
-                                "Invalid motion: {:?}",
-                                reaction.motion
-                            );
-                            Status::new(
-                                tonic::Code::Internal,
-                                "Failed to parse motion".to_string(),
-                            )
-                        })?;
-
-                    let cry =
-                        Cry::from_str_name(&reaction.cry).ok_or_else(|| {
-                            tracing::error!("Invalid cry: {:?}", reaction.cry);
-                            Status::new(
-                                tonic::Code::Internal,
-                                "Failed to parse cry".to_string(),
-                            )
-                        })?;
-
-                    let state = creature_rpc::State {
-                        emotion: emotion as i32,
-                        motion: motion as i32,
-                        cry: cry as i32,
-                        friendliness: reaction.friendliness,
-                    };
+                    apply_affect(&mut context, &definition, &mut reaction);
+                    apply_relationship(
+                        &mut context,
+                        &definition,
+                        author.as_deref(),
+                        last_author.as_deref(),
+                        &mut reaction,
+                    )
+                    .await?;
+                    context.policies.apply(
+                        match &author {
+                            | Some(author) => PolicyKey::Author(author.clone()),
+                            | None => PolicyKey::Idle,
+                        },
+                        &definition.policy,
+                        &definition,
+                        &mut reaction,
+                    );
+                    settle_needs(&mut context, &definition, &mut reaction)
+                        .await?;
+                    remember_reaction(
+                        &context,
+                        &definition,
+                        author.unwrap_or_else(|| definition.id.clone()),
+                        visibility,
+                        &occasion,
+                        &reaction,
+                    );
+
+                    let state = to_state(reaction);
 
                     tracing::info!("Succeeded to react: {:?}", state);
 
-                    Ok(state)
+                    Ok((state, response.usage.total_tokens))
                 },
             },
         },
     }
 }
 
-fn combine_to_string(points: Vec<ScoredPoint>) -> String {
-    let mut result = String::new();
+/// Fills the fixed fields for clients that predate the generic ones.
+fn to_state(reaction: Reaction) -> creature_rpc::State {
+    let emotion = reaction
+        .actions
+        .get("emotion")
+        .and_then(|value| Emotion::from_str_name(value))
+        .unwrap_or(Emotion::Neutral);
+    let motion = reaction
+        .actions
+        .get("motion")
+        .and_then(|value| Motion::from_str_name(value))
+        .unwrap_or(Motion::Neutral);
+    let cry = reaction
+        .actions
+        .get("cry")
+        .and_then(|value| Cry::from_str_name(value))
+        .unwrap_or(Cry::None);
+    let friendliness = reaction
+        .properties
+        .get("friendliness")
+        .copied()
+        .unwrap_or_default();
+
+    creature_rpc::State {
+        emotion: emotion as i32,
+        motion: motion as i32,
+        cry: cry as i32,
+        friendliness,
+        actions: reaction
+            .actions
+            .into_iter()
+            .collect(),
+        properties: reaction
+            .properties
+            .into_iter()
+            .collect(),
+        sequence: reaction
+            .sequence
+            .into_iter()
+            .map(|action| creature_rpc::TimedAction {
+                channel: action.channel,
+                value: action.value,
+                start_offset_ms: action.start_offset_ms,
+                duration_ms: action.duration_ms,
+                loop_count: action.loop_count,
+            })
+            .collect(),
+        emotion_intensities: reaction
+            .intensities
+            .into_iter()
+            .collect(),
+        needs: reaction
+            .needs
+            .into_iter()
+            .collect(),
+        throttle: None,
+    }
+}
+
+/// Current emotion intensities for the prompt, weakest ones omitted.
+fn mood(
+    context: &mut RpcContext,
+    definition: &CreatureDefinition,
+) -> Vec<EmotionContext> {
+    let (Some(settings), Some(affect)) = (
+        &definition.affect,
+        context.affect.as_mut(),
+    ) else {
+        return Vec::new();
+    };
+    let Some(channel) = definition.channel(&settings.channel) else {
+        return Vec::new();
+    };
+    affect.decay(settings);
+
+    affect
+        .snapshot(channel)
+        .into_iter()
+        .filter(|(_, intensity)| *intensity > 0.0)
+        .map(|(name, intensity)| EmotionContext {
+            name,
+            intensity,
+        })
+        .collect()
+}
+
+/// Current relationship toward the author for the prompt.
+fn friendliness(
+    context: &RpcContext,
+    definition: &CreatureDefinition,
+    author: Option<&str>,
+) -> Option<f64> {
+    let settings = definition
+        .relationship
+        .as_ref()?;
+    let value = context
+        .relationships
+        .as_ref()?
+        .get(settings, author?);
+
+    Some(value)
+}
+
+fn needs(
+    context: &RpcContext,
+    definition: &CreatureDefinition,
+) -> Vec<NeedContext> {
+    let (Some(settings), Some(needs)) = (
+        &definition.needs,
+        context.needs.as_ref(),
+    ) else {
+        return Vec::new();
+    };
+    let values = needs.snapshot(settings);
+
+    settings
+        .variables
+        .iter()
+        .map(|variable| NeedContext {
+            name: variable.name.clone(),
+            value: values
+                .get(&variable.name)
+                .copied()
+                .unwrap_or(variable.initial),
+            description: variable.description.clone(),
+        })
+        .collect()
+}
+
+/// Advances the needs of the creature to now and applies what the stimulus
+/// does to them, before the LLM sees them.
+fn feel_needs(
+    context: &mut RpcContext,
+    definition: &CreatureDefinition,
+    stimulus: &Stimulus,
+) {
+    let (Some(settings), Some(needs)) = (
+        &definition.needs,
+        context.needs.as_mut(),
+    ) else {
+        return;
+    };
+
+    needs.tick(settings);
+    if let Stimulus::Talking(talking) = stimulus {
+        match &talking.input {
+            | Some(Input::Message(_)) => {
+                needs.trigger(settings, Trigger::Speech)
+            },
+            | Some(Input::Event(event)) => {
+                needs.trigger(settings, Trigger::Event(&event.kind))
+            },
+            | None => {},
+        }
+    }
+}
+
+/// Applies the actions the creature plays to its needs and persists them.
+async fn settle_needs(
+    context: &mut RpcContext,
+    definition: &CreatureDefinition,
+    reaction: &mut Reaction,
+) -> Result<(), Status> {
+    let (Some(settings), Some(needs)) = (
+        &definition.needs,
+        context.needs.as_mut(),
+    ) else {
+        return Ok(());
+    };
+
+    for (channel, value) in &reaction.actions {
+        needs.trigger(
+            settings,
+            Trigger::Action(channel, value),
+        );
+    }
+    for action in &reaction.sequence {
+        needs.trigger(
+            settings,
+            Trigger::Action(&action.channel, &action.value),
+        );
+    }
+
+    needs
+        .save()
+        .await
+        .map_err(|error| {
+            tracing::error!("Failed to save needs: {:?}", error);
+            Status::new(
+                tonic::Code::Internal,
+                "Failed to save needs".to_string(),
+            )
+        })?;
+
+    if settings.expose {
+        reaction.needs = needs.snapshot(settings);
+    }
+
+    Ok(())
+}
+
+/// Applies the change proposed by the LLM to the relationship with the
+/// author and reports the value kept by the server.
+async fn apply_relationship(
+    context: &mut RpcContext,
+    definition: &CreatureDefinition,
+    author: Option<&str>,
+    last_author: Option<&str>,
+    reaction: &mut Reaction,
+) -> Result<(), Status> {
+    let (Some(settings), Some(relationships)) = (
+        &definition.relationship,
+        context.relationships.as_mut(),
+    ) else {
+        return Ok(());
+    };
+    let Some(property) = definition.property(&settings.property) else {
+        return Ok(());
+    };
+
+    let value = match author {
+        | Some(author) => relationships
+            .update(
+                settings,
+                property,
+                author,
+                reaction.relationship_delta,
+            )
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to update relationship: {:?}",
+                    error
+                );
+                Status::new(
+                    tonic::Code::Internal,
+                    "Failed to update relationship".to_string(),
+                )
+            })?,
+        // Nobody to change the relationship with on idle, so it reports
+        // the one toward the last author of the stream.
+        | None => last_author.map_or(settings.initial, |last_author| {
+            relationships.get(settings, last_author)
+        }),
+    };
+
+    reaction
+        .properties
+        .insert(property.name.clone(), value);
+
+    Ok(())
+}
+
+/// Nudges the emotion intensities of the creature and replaces the emotion
+/// picked by the LLM with the dominant one.
+fn apply_affect(
+    context: &mut RpcContext,
+    definition: &CreatureDefinition,
+    reaction: &mut Reaction,
+) {
+    let (Some(settings), Some(affect)) = (
+        &definition.affect,
+        context.affect.as_mut(),
+    ) else {
+        return;
+    };
+    let Some(channel) = definition.channel(&settings.channel) else {
+        return;
+    };
 
-    for point in points {
-        let record = Record::from_payload(point.payload);
-        result += &format!(
-            "  - {} (score: {})\n",
-            record.text, point.score
+    affect.nudge(settings, &reaction.nudges);
+    reaction.intensities = affect.snapshot(channel);
+
+    if let Some(dominant) = affect.dominant(settings, channel) {
+        let picked = reaction
+            .actions
+            .insert(channel.name.clone(), dominant.clone());
+        if picked.as_ref() != Some(&dominant) {
+            tracing::info!(
+                "Derived {} {} in place of {:?}",
+                channel.name,
+                dominant,
+                picked
+            );
+        }
+    }
+}
+
+/// Stores how the creature reacted in long-term memory, e.g. "I felt SAD
+/// (motion: NO) when Mochineko said "Goodbye"", so that it can recall how it
+/// felt later. Runs in the background so that embedding adds no latency to
+/// the reply, and a failure only loses the memory.
+fn remember_reaction(
+    context: &RpcContext,
+    definition: &CreatureDefinition,
+    author: String,
+    visibility: Visibility,
+    occasion: &str,
+    reaction: &Reaction,
+) {
+    let text = format!(
+        "{} {}",
+        describe_reaction(definition, reaction),
+        occasion
+    );
+    let importance = retrieval::rate_importance(&text, RecordKind::Reaction);
+
+    let record = Record::new(
+        text,
+        author,
+        RecordKind::Reaction,
+        visibility,
+        importance,
+    );
+    let mut long_memory = context
+        .long_memory
+        .with_namespace(
+            context
+                .long_memory
+                .namespace
+                .clone(),
         );
+
+    tokio::spawn(async move {
+        if let Err(error) = long_memory
+            .upsert(record)
+            .await
+        {
+            tracing::error!(
+                "Failed to upsert reaction to long memory: {:?}",
+                error
+            );
+        }
+    });
+}
+
+/// Renders the actions of a reaction other than fallbacks in the first
+/// person, naming the value of the affect channel as a feeling with the
+/// channel prefix dropped, e.g. "I felt SAD (motion: NO, cry: SAD)".
+fn describe_reaction(
+    definition: &CreatureDefinition,
+    reaction: &Reaction,
+) -> String {
+    let affect_channel = definition
+        .affect
+        .as_ref()
+        .map(|settings| settings.channel.as_str());
+
+    let mut feeling = None;
+    let mut actions = Vec::new();
+    for (channel, value) in &reaction.actions {
+        let fallback = definition
+            .channel(channel)
+            .and_then(|channel| channel.fallback.as_deref());
+        if fallback == Some(value.as_str()) {
+            continue;
+        }
+
+        let prefix = format!("{}_", channel.to_uppercase());
+        let value = value
+            .strip_prefix(&prefix)
+            .unwrap_or(value);
+        if affect_channel == Some(channel.as_str()) {
+            feeling = Some(value);
+        } else {
+            actions.push(format!("{}: {}", channel, value));
+        }
+    }
+
+    let mut description = match feeling {
+        | Some(feeling) => format!("I felt {}", feeling),
+        | None => "I stayed calm".to_string(),
+    };
+    if !actions.is_empty() {
+        description += &format!(" ({})", actions.join(", "));
+    }
+
+    description
+}
+
+/// Renders what the creature reacted to as the end of a sentence, e.g.
+/// "when Mochineko said "Hello"".
+fn describe_occasion(
+    stimulus: &Stimulus,
+    query: &str,
+) -> String {
+    match stimulus {
+        | Stimulus::Talking(talking) => match talking.input {
+            | Some(Input::Event(_)) => format!("when {}", query),
+            | _ => format!(
+                "when {} said \"{}\"",
+                talking.author, query
+            ),
+        },
+        | Stimulus::Idle {
+            ..
+        } => "on my own".to_string(),
+    }
+}
+
+/// Renders an event as a sentence, e.g. "Mochineko feed creature (food:
+/// apple)".
+fn describe_event(event: &creature_rpc::WorldEvent) -> String {
+    let mut description = [
+        event.actor.as_str(),
+        event.kind.as_str(),
+        event.target.as_str(),
+    ]
+    .iter()
+    .filter(|word| !word.is_empty())
+    .copied()
+    .collect::<Vec<_>>()
+    .join(" ");
+
+    if !event.parameters.is_empty() {
+        let mut parameters = event
+            .parameters
+            .iter()
+            .map(|(key, value)| format!("{}: {}", key, value))
+            .collect::<Vec<_>>();
+        parameters.sort();
+        description += &format!(" ({})", parameters.join(", "));
+    }
+
+    description
+}
+
+fn to_memories(ranked: Vec<(Record, f64)>) -> Vec<MemoryContext> {
+    let mut memories = Vec::new();
+
+    for (record, score) in ranked {
+        memories.push(MemoryContext {
+            text: record.text,
+            author: record.author,
+            datetime: record
+                .datetime
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string(),
+            kind: record.kind.parse_to_string(),
+            visibility: record
+                .visibility
+                .parse_to_string(),
+            score: score as f32,
+        });
     }
 
-    result
+    memories
 }
@@ -0,0 +1,473 @@
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::creature::definition::CreatureDefinition;
+use crate::creature::storage::{load_json, save_json};
+
+/// Internal drives of a creature, e.g. hunger, energy or boredom.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct NeedsSettings {
+    pub(crate) variables: Vec<NeedVariable>,
+    #[serde(default)]
+    pub(crate) effects: Vec<NeedEffect>,
+    /// Sends the needs to clients in `State`.
+    #[serde(default)]
+    pub(crate) expose: bool,
+    /// JSON file the needs are persisted to. Defaults to
+    /// `needs/<creature id>.json`.
+    #[serde(default)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct NeedVariable {
+    pub(crate) name: String,
+    /// Meaning shown to the LLM.
+    #[serde(default)]
+    pub(crate) description: Option<String>,
+    #[serde(default)]
+    pub(crate) initial: f64,
+    #[serde(default)]
+    pub(crate) minimum: f64,
+    #[serde(default = "default_maximum")]
+    pub(crate) maximum: f64,
+    /// Change over an hour of wall-clock time, e.g. hunger grows.
+    #[serde(default)]
+    pub(crate) change_per_hour: f64,
+}
+
+fn default_maximum() -> f64 {
+    1.0
+}
+
+/// Change of a need when something happens. Exactly one of `speech`,
+/// `event` or `channel` and `value` names what.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct NeedEffect {
+    pub(crate) need: String,
+    pub(crate) change: f64,
+    /// Someone talks to the creature.
+    #[serde(default)]
+    pub(crate) speech: bool,
+    /// Kind of a world event, e.g. "feed".
+    #[serde(default)]
+    pub(crate) event: Option<String>,
+    /// Action the creature plays, e.g. motion MOTION_EATING.
+    #[serde(default)]
+    pub(crate) channel: Option<String>,
+    #[serde(default)]
+    pub(crate) value: Option<String>,
+}
+
+/// What may change the needs.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Trigger<'a> {
+    Speech,
+    Event(&'a str),
+    Action(&'a str, &'a str),
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct SavedNeeds {
+    values: BTreeMap<String, f64>,
+    updated_at_ms: i64,
+}
+
+/// Current needs of one creature.
+#[derive(Debug)]
+pub(crate) struct Needs {
+    path: PathBuf,
+    saved: SavedNeeds,
+}
+
+impl NeedsSettings {
+    pub(crate) fn validate(
+        &self,
+        definition: &CreatureDefinition,
+    ) -> Result<()> {
+        let mut names = HashSet::new();
+        for variable in &self.variables {
+            if !names.insert(variable.name.as_str()) {
+                return Err(anyhow!(
+                    "Duplicate need: {}",
+                    variable.name
+                ));
+            }
+            if variable.minimum >= variable.maximum {
+                return Err(anyhow!(
+                    "Need {} must have minimum below maximum",
+                    variable.name
+                ));
+            }
+            if !(variable.minimum..=variable.maximum)
+                .contains(&variable.initial)
+            {
+                return Err(anyhow!(
+                    "Initial {} is out of range",
+                    variable.name
+                ));
+            }
+        }
+
+        for effect in &self.effects {
+            if self
+                .variable(&effect.need)
+                .is_none()
+            {
+                return Err(anyhow!("Unknown need: {}", effect.need));
+            }
+
+            let triggers = [
+                effect.speech,
+                effect.event.is_some(),
+                effect.channel.is_some() || effect.value.is_some(),
+            ]
+            .iter()
+            .filter(|trigger| **trigger)
+            .count();
+            if triggers != 1 {
+                return Err(anyhow!(
+                    "Effect on {} must have exactly one trigger",
+                    effect.need
+                ));
+            }
+
+            if let (Some(channel), Some(value)) =
+                (&effect.channel, &effect.value)
+            {
+                let known = definition
+                    .channel(channel)
+                    .is_some_and(|channel| {
+                        channel
+                            .values
+                            .iter()
+                            .any(|allowed| &allowed.name == value)
+                    });
+                if !known {
+                    return Err(anyhow!(
+                        "Unknown action {} {} of effect on {}",
+                        channel,
+                        value,
+                        effect.need
+                    ));
+                }
+            } else if effect.channel.is_some() || effect.value.is_some() {
+                return Err(anyhow!(
+                    "Action effect on {} needs both channel and value",
+                    effect.need
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn variable(
+        &self,
+        name: &str,
+    ) -> Option<&NeedVariable> {
+        self.variables
+            .iter()
+            .find(|variable| variable.name == name)
+    }
+}
+
+impl NeedEffect {
+    fn is_triggered_by(
+        &self,
+        trigger: Trigger,
+    ) -> bool {
+        match trigger {
+            | Trigger::Speech => self.speech,
+            | Trigger::Event(kind) => self.event.as_deref() == Some(kind),
+            | Trigger::Action(channel, value) => {
+                self.channel.as_deref() == Some(channel)
+                    && self.value.as_deref() == Some(value)
+            },
+        }
+    }
+}
+
+impl Needs {
+    #[tracing::instrument(
+        name = "creature.needs.load",
+        err,
+        skip(creature_id, settings)
+    )]
+    pub(crate) fn load(
+        creature_id: &str,
+        settings: &NeedsSettings,
+    ) -> Result<Self> {
+        let path = settings
+            .path
+            .clone()
+            .unwrap_or_else(|| {
+                Path::new("needs").join(format!("{}.json", creature_id))
+            });
+
+        let mut saved: SavedNeeds = load_json(&path)?;
+        for variable in &settings.variables {
+            saved
+                .values
+                .entry(variable.name.clone())
+                .or_insert(variable.initial);
+        }
+        if saved.updated_at_ms == 0 {
+            saved.updated_at_ms = now_ms();
+        }
+
+        tracing::info!(
+            "Loaded needs of creature {}: {:?}",
+            creature_id,
+            saved.values
+        );
+
+        Ok(Self {
+            path,
+            saved,
+        })
+    }
+
+    /// Advances the needs by the wall-clock time passed, including the time
+    /// the server was down.
+    pub(crate) fn tick(
+        &mut self,
+        settings: &NeedsSettings,
+    ) {
+        let now = now_ms();
+        let hours =
+            (now - self.saved.updated_at_ms).max(0) as f64 / 3_600_000.0;
+        self.saved.updated_at_ms = now;
+
+        for variable in &settings.variables {
+            self.change(
+                variable,
+                variable.change_per_hour * hours,
+            );
+        }
+    }
+
+    pub(crate) fn trigger(
+        &mut self,
+        settings: &NeedsSettings,
+        trigger: Trigger,
+    ) {
+        for effect in &settings.effects {
+            if !effect.is_triggered_by(trigger) {
+                continue;
+            }
+            if let Some(variable) = settings.variable(&effect.need) {
+                tracing::info!(
+                    "Changed need {} by {} on {:?}",
+                    variable.name,
+                    effect.change,
+                    trigger
+                );
+                self.change(variable, effect.change);
+            }
+        }
+    }
+
+    /// Values of the needs the current definition has, leaving out saved
+    /// ones it dropped.
+    pub(crate) fn snapshot(
+        &self,
+        settings: &NeedsSettings,
+    ) -> BTreeMap<String, f64> {
+        settings
+            .variables
+            .iter()
+            .map(|variable| {
+                let value = self
+                    .saved
+                    .values
+                    .get(&variable.name)
+                    .copied()
+                    .unwrap_or(variable.initial);
+                (variable.name.clone(), value)
+            })
+            .collect()
+    }
+
+    pub(crate) async fn save(&self) -> Result<()> {
+        save_json(&self.path, &self.saved).await
+    }
+
+    fn change(
+        &mut self,
+        variable: &NeedVariable,
+        change: f64,
+    ) {
+        let value = self
+            .saved
+            .values
+            .entry(variable.name.clone())
+            .or_insert(variable.initial);
+        *value = (*value + change).clamp(variable.minimum, variable.maximum);
+    }
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::creature::definition::tests::definition;
+
+    const NEEDS: &str = r#"
+[needs]
+
+[[needs.variables]]
+name = "hunger"
+initial = 0.5
+change_per_hour = 0.2
+
+[[needs.variables]]
+name = "energy"
+initial = 0.5
+
+[[needs.effects]]
+need = "hunger"
+change = -0.3
+event = "feed"
+
+[[needs.effects]]
+need = "hunger"
+change = -0.1
+channel = "motion"
+value = "MOTION_EATING"
+
+[[needs.effects]]
+need = "energy"
+change = 0.8
+speech = true
+"#;
+
+    fn needs() -> (NeedsSettings, Needs) {
+        let mut settings = definition(NEEDS)
+            .unwrap()
+            .needs
+            .unwrap();
+        // Never written, as the tests do not save.
+        settings.path = Some(std::env::temp_dir().join(format!(
+            "needs_{}.json",
+            uuid::Uuid::new_v4().simple()
+        )));
+        let needs = Needs::load("test", &settings).unwrap();
+        (settings, needs)
+    }
+
+    #[test]
+    fn tick_changes_by_the_time_passed() {
+        let (settings, mut needs) = needs();
+        needs.saved.updated_at_ms -= 3_600_000;
+
+        needs.tick(&settings);
+
+        let values = needs.snapshot(&settings);
+        assert!((values["hunger"] - 0.7).abs() < 1e-3);
+        assert_eq!(values["energy"], 0.5);
+    }
+
+    #[test]
+    fn tick_clamps_to_the_range() {
+        let (settings, mut needs) = needs();
+        needs.saved.updated_at_ms -= 10 * 3_600_000;
+
+        needs.tick(&settings);
+
+        assert_eq!(needs.snapshot(&settings)["hunger"], 1.0);
+    }
+
+    #[test]
+    fn trigger_applies_matching_effects() {
+        let (settings, mut needs) = needs();
+
+        needs.trigger(&settings, Trigger::Event("feed"));
+        needs.trigger(&settings, Trigger::Event("pet"));
+        needs.trigger(
+            &settings,
+            Trigger::Action("motion", "MOTION_EATING"),
+        );
+        needs.trigger(
+            &settings,
+            Trigger::Action("motion", "MOTION_JUMP"),
+        );
+        needs.trigger(&settings, Trigger::Speech);
+
+        let values = needs.snapshot(&settings);
+        assert!((values["hunger"] - 0.1).abs() < 1e-9);
+        assert_eq!(values["energy"], 1.0);
+    }
+
+    #[test]
+    fn snapshot_leaves_out_dropped_needs() {
+        let (settings, mut needs) = needs();
+        needs
+            .saved
+            .values
+            .insert("thirst".to_string(), 0.9);
+
+        let values = needs.snapshot(&settings);
+
+        assert_eq!(
+            values
+                .keys()
+                .collect::<Vec<_>>(),
+            ["energy", "hunger"]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_needs() {
+        let invalid = [
+            r#"
+[needs]
+variables = [{ name = "hunger" }, { name = "hunger" }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger", minimum = 1.0, maximum = 0.0 }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger", initial = 2.0 }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger" }]
+effects = [{ need = "thirst", change = 0.1, speech = true }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger" }]
+effects = [{ need = "hunger", change = 0.1, speech = true, event = "feed" }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger" }]
+effects = [{ need = "hunger", change = 0.1 }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger" }]
+effects = [{ need = "hunger", change = 0.1, channel = "motion" }]
+"#,
+            r#"
+[needs]
+variables = [{ name = "hunger" }]
+effects = [{ need = "hunger", change = 0.1, channel = "motion", value = "MOTION_FLY" }]
+"#,
+        ];
+
+        assert!(definition(NEEDS).is_ok());
+        for text in invalid {
+            assert!(definition(text).is_err(), "{}", text);
+        }
+    }
+}
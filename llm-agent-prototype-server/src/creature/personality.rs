@@ -0,0 +1,278 @@
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::creature::definition::CreatureDefinition;
+use crate::creature::prompt::{PersonalityContext, TraitContext};
+
+/// Traits a personality may score, the Big Five.
+const TRAITS: [&str; 5] = [
+    "openness",
+    "conscientiousness",
+    "extraversion",
+    "agreeableness",
+    "neuroticism",
+];
+
+/// Structured persona of a creature, rendered into the system prompt and
+/// used to tune its dynamics.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Personality {
+    /// Scores in `[0, 1]`, e.g. the Big Five. Omitted traits count as 0.5.
+    #[serde(default)]
+    pub(crate) traits: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub(crate) speech_style: Option<String>,
+    #[serde(default)]
+    pub(crate) likes: Vec<String>,
+    #[serde(default)]
+    pub(crate) dislikes: Vec<String>,
+    /// Emotion intensities the creature settles at, replacing the affect
+    /// baseline.
+    #[serde(default)]
+    pub(crate) baseline_mood: BTreeMap<String, f64>,
+}
+
+impl Personality {
+    pub(crate) fn validate(
+        &self,
+        definition: &CreatureDefinition,
+    ) -> Result<()> {
+        for (name, score) in &self.traits {
+            if !TRAITS.contains(&name.as_str()) {
+                return Err(anyhow!(
+                    "Unknown trait {}, expected one of {}",
+                    name,
+                    TRAITS.join(", ")
+                ));
+            }
+            if !(0.0..=1.0).contains(score) {
+                return Err(anyhow!(
+                    "Trait {} must be between 0 and 1",
+                    name
+                ));
+            }
+        }
+        if !self.baseline_mood.is_empty() && definition.affect.is_none() {
+            return Err(anyhow!(
+                "baseline_mood needs affect settings to take effect"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Scales the dynamics of the definition by the traits, so that a
+    /// neutral trait of 0.5 keeps the configured value:
+    /// - neuroticism: stronger and longer emotions
+    /// - agreeableness: faster growing relationships
+    /// - extraversion: sooner acting on silence
+    pub(crate) fn shape(
+        &self,
+        definition: &mut CreatureDefinition,
+    ) {
+        let neuroticism = self.factor("neuroticism");
+        let agreeableness = self.factor("agreeableness");
+        let extraversion = self.factor("extraversion");
+
+        if let Some(affect) = &mut definition.affect {
+            if !self.baseline_mood.is_empty() {
+                affect.baseline = self.baseline_mood.clone();
+            }
+            affect.gain *= neuroticism;
+            affect.half_life_seconds *= neuroticism;
+            for half_life in affect.half_lives.values_mut() {
+                *half_life *= neuroticism;
+            }
+        }
+
+        if let Some(relationship) = &mut definition.relationship {
+            relationship.smoothing =
+                (relationship.smoothing * agreeableness).min(1.0);
+        }
+
+        if let Some(idle) = &mut definition.idle {
+            idle.after_silence_seconds = idle
+                .after_silence_seconds
+                .map(|seconds| ((seconds as f64 / extraversion) as u64).max(1));
+        }
+
+        tracing::info!(
+            "Shaped creature {} by personality: affect {:?}, relationship {:?}, idle {:?}",
+            definition.id,
+            definition.affect,
+            definition.relationship,
+            definition.idle
+        );
+    }
+
+    /// Persona for the system prompt template.
+    pub(crate) fn describe(&self) -> PersonalityContext {
+        PersonalityContext {
+            traits: self
+                .traits
+                .iter()
+                .map(|(name, score)| TraitContext {
+                    name: name.clone(),
+                    level: describe_score(*score).to_string(),
+                    score: *score,
+                })
+                .collect(),
+            speech_style: self.speech_style.clone(),
+            likes: self.likes.clone(),
+            dislikes: self.dislikes.clone(),
+            usual_mood: self
+                .baseline_mood
+                .iter()
+                .max_by(|(_, left), (_, right)| left.total_cmp(right))
+                .map(|(mood, _)| mood.clone()),
+        }
+    }
+
+    /// Multiplier in `[0.5, 1.5]` of a trait.
+    fn factor(
+        &self,
+        name: &str,
+    ) -> f64 {
+        0.5 + self
+            .traits
+            .get(name)
+            .copied()
+            .unwrap_or(0.5)
+    }
+}
+
+fn describe_score(score: f64) -> &'static str {
+    match score {
+        | score if score < 0.2 => "very low",
+        | score if score < 0.4 => "low",
+        | score if score < 0.6 => "moderate",
+        | score if score < 0.8 => "high",
+        | _ => "very high",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::creature::definition::tests::definition;
+
+    /// Definition shaped by every trait at the score.
+    fn shaped(
+        score: f64,
+        after_silence_seconds: u64,
+    ) -> CreatureDefinition {
+        definition(&format!(
+            r#"
+[personality]
+traits = {{ openness = {score}, conscientiousness = {score}, extraversion = {score}, agreeableness = {score}, neuroticism = {score} }}
+baseline_mood = {{ EMOTION_HAPPY = 0.1 }}
+
+[affect]
+half_life_seconds = 100
+half_lives = {{ EMOTION_SAD = 10 }}
+gain = 0.5
+
+[relationship]
+smoothing = 0.8
+
+[idle]
+after_silence_seconds = {after_silence_seconds}
+"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn neutral_traits_keep_the_settings() {
+        let definition = shaped(0.5, 60);
+
+        let affect = definition.affect.unwrap();
+        assert_eq!(affect.gain, 0.5);
+        assert_eq!(affect.half_life_seconds, 100.0);
+        assert_eq!(affect.half_lives["EMOTION_SAD"], 10.0);
+        assert_eq!(
+            affect.baseline,
+            BTreeMap::from([("EMOTION_HAPPY".to_string(), 0.1)])
+        );
+        assert_eq!(
+            definition
+                .relationship
+                .unwrap()
+                .smoothing,
+            0.8
+        );
+        assert_eq!(
+            definition
+                .idle
+                .unwrap()
+                .after_silence_seconds,
+            Some(60)
+        );
+    }
+
+    #[test]
+    fn high_traits_strengthen_emotions_and_shorten_silence() {
+        let definition = shaped(1.0, 60);
+
+        let affect = definition.affect.unwrap();
+        assert_eq!(affect.gain, 0.75);
+        assert_eq!(affect.half_life_seconds, 150.0);
+        assert_eq!(affect.half_lives["EMOTION_SAD"], 15.0);
+        // Smoothing never goes past a full step.
+        assert_eq!(
+            definition
+                .relationship
+                .unwrap()
+                .smoothing,
+            1.0
+        );
+        assert_eq!(
+            definition
+                .idle
+                .unwrap()
+                .after_silence_seconds,
+            Some(40)
+        );
+    }
+
+    #[test]
+    fn low_traits_weaken_emotions_and_lengthen_silence() {
+        let definition = shaped(0.0, 60);
+
+        let affect = definition.affect.unwrap();
+        assert_eq!(affect.gain, 0.25);
+        assert_eq!(affect.half_life_seconds, 50.0);
+        assert_eq!(affect.half_lives["EMOTION_SAD"], 5.0);
+        assert!(
+            (definition
+                .relationship
+                .unwrap()
+                .smoothing
+                - 0.4)
+                .abs()
+                < 1e-9
+        );
+        assert_eq!(
+            definition
+                .idle
+                .unwrap()
+                .after_silence_seconds,
+            Some(120)
+        );
+    }
+
+    #[test]
+    fn silence_stays_at_least_a_second() {
+        let definition = shaped(1.0, 1);
+
+        assert_eq!(
+            definition
+                .idle
+                .unwrap()
+                .after_silence_seconds,
+            Some(1)
+        );
+    }
+}
@@ -0,0 +1,900 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+use crate::creature::definition::{CreatureDefinition, Reaction};
+
+/// Rules applied to every reaction of the LLM before it is sent.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub(crate) struct PolicySettings {
+    #[serde(default)]
+    pub(crate) forbidden_transitions: Vec<ForbiddenTransition>,
+    #[serde(default)]
+    pub(crate) cooldowns: Vec<Cooldown>,
+    #[serde(default)]
+    pub(crate) hysteresis: Vec<Hysteresis>,
+    #[serde(default)]
+    pub(crate) gates: Vec<Gate>,
+}
+
+/// A value that may not follow another one, or any value when `from` is
+/// omitted.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct ForbiddenTransition {
+    pub(crate) channel: String,
+    #[serde(default)]
+    pub(crate) from: Option<String>,
+    pub(crate) to: String,
+}
+
+/// A value that may not play again within the time.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Cooldown {
+    pub(crate) channel: String,
+    pub(crate) value: String,
+    pub(crate) seconds: u64,
+}
+
+/// A channel that changes only after the LLM proposes the same new value
+/// for several turns in a row.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Hysteresis {
+    pub(crate) channel: String,
+    pub(crate) turns: u32,
+}
+
+/// A value allowed only while a property is within the range, e.g. no
+/// attack while friendly.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Gate {
+    pub(crate) channel: String,
+    pub(crate) value: String,
+    pub(crate) property: String,
+    #[serde(default)]
+    pub(crate) minimum: Option<f64>,
+    #[serde(default)]
+    pub(crate) maximum: Option<f64>,
+}
+
+/// Number of tracked policy states above which stale ones are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// Time after which the state of a quiet author is dropped, unless a
+/// cooldown lasts longer.
+const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Whose reactions a policy state watches.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum PolicyKey {
+    Author(String),
+    /// Reactions of the creature on its own.
+    Idle,
+}
+
+/// Policy states of one creature, since each author watches their own
+/// reactions.
+#[derive(Debug, Default)]
+pub(crate) struct Policies {
+    states: HashMap<PolicyKey, PolicyState>,
+}
+
+/// What the policy remembers of past reactions of one creature to one
+/// author.
+#[derive(Debug, Default)]
+pub(crate) struct PolicyState {
+    /// Last sent value of each channel.
+    current: HashMap<String, String>,
+    played_at: HashMap<(String, String), Instant>,
+    /// New value waiting for hysteresis and the turns it was proposed.
+    proposed: HashMap<String, (String, u32)>,
+    applied_at: Option<Instant>,
+}
+
+impl PolicySettings {
+    pub(crate) fn validate(
+        &self,
+        definition: &CreatureDefinition,
+    ) -> Result<()> {
+        let check_value = |channel: &str, value: &str| -> Result<()> {
+            let found = definition
+                .channel(channel)
+                .ok_or_else(|| {
+                    anyhow!("Unknown policy channel: {}", channel)
+                })?;
+            if !found
+                .values
+                .iter()
+                .any(|allowed| allowed.name == value)
+            {
+                return Err(anyhow!(
+                    "Unknown policy value {} in channel {}",
+                    value,
+                    channel
+                ));
+            }
+            Ok(())
+        };
+
+        for transition in &self.forbidden_transitions {
+            check_value(&transition.channel, &transition.to)?;
+            if let Some(from) = &transition.from {
+                check_value(&transition.channel, from)?;
+            }
+        }
+        for cooldown in &self.cooldowns {
+            check_value(&cooldown.channel, &cooldown.value)?;
+            if cooldown.seconds == 0 {
+                return Err(anyhow!(
+                    "Cooldown of {} must be positive",
+                    cooldown.value
+                ));
+            }
+        }
+        for hysteresis in &self.hysteresis {
+            if definition
+                .channel(&hysteresis.channel)
+                .is_none()
+            {
+                return Err(anyhow!(
+                    "Unknown policy channel: {}",
+                    hysteresis.channel
+                ));
+            }
+            if hysteresis.turns == 0 {
+                return Err(anyhow!(
+                    "Hysteresis of {} must be positive",
+                    hysteresis.channel
+                ));
+            }
+        }
+        for gate in &self.gates {
+            check_value(&gate.channel, &gate.value)?;
+            if definition
+                .property(&gate.property)
+                .is_none()
+            {
+                return Err(anyhow!(
+                    "Unknown policy property: {}",
+                    gate.property
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Policies {
+    /// Applies the rules with the state of the key.
+    pub(crate) fn apply(
+        &mut self,
+        key: PolicyKey,
+        settings: &PolicySettings,
+        definition: &CreatureDefinition,
+        reaction: &mut Reaction,
+    ) {
+        self.prune(settings);
+
+        self.states
+            .entry(key)
+            .or_default()
+            .apply(settings, definition, reaction);
+    }
+
+    fn prune(
+        &mut self,
+        settings: &PolicySettings,
+    ) {
+        if self.states.len() < PRUNE_THRESHOLD {
+            return;
+        }
+
+        let stale_after = settings
+            .cooldowns
+            .iter()
+            .map(|cooldown| Duration::from_secs(cooldown.seconds))
+            .fold(STALE_AFTER, Duration::max);
+        let now = Instant::now();
+        self.states
+            .retain(|_, state| {
+                state
+                    .applied_at
+                    .is_some_and(|at| now.duration_since(at) < stale_after)
+            });
+    }
+}
+
+impl PolicyState {
+    /// Replaces the actions the rules reject by the channel fallback, or
+    /// by the current value when the fallback is rejected as well, and
+    /// drops rejected actions of the sequence.
+    pub(crate) fn apply(
+        &mut self,
+        settings: &PolicySettings,
+        definition: &CreatureDefinition,
+        reaction: &mut Reaction,
+    ) {
+        let now = Instant::now();
+        self.applied_at = Some(now);
+
+        for channel in &definition.channels {
+            let Some(proposed) = reaction
+                .actions
+                .get(&channel.name)
+                .cloned()
+            else {
+                continue;
+            };
+            let current = self
+                .current
+                .get(&channel.name)
+                .cloned();
+
+            let mut value = proposed.clone();
+            if let Some(rule) = self.reject(
+                settings,
+                &channel.name,
+                &value,
+                current.as_deref(),
+                &reaction.properties,
+                now,
+            ) {
+                let replacement = [
+                    &channel.fallback,
+                    &current,
+                ]
+                .into_iter()
+                .flatten()
+                .find(|candidate| {
+                    self.reject(
+                        settings,
+                        &channel.name,
+                        candidate,
+                        current.as_deref(),
+                        &reaction.properties,
+                        now,
+                    )
+                    .is_none()
+                });
+                // Staying put is the least surprising when every
+                // replacement is rejected too.
+                let replacement = match replacement {
+                    | Some(replacement) => replacement.clone(),
+                    | None => {
+                        tracing::warn!(
+                            "Policy rejected every replacement of {} {}",
+                            channel.name,
+                            value
+                        );
+                        current
+                            .clone()
+                            .unwrap_or_else(|| value.clone())
+                    },
+                };
+                tracing::info!(
+                    "Policy {} replaced {} {} by {}",
+                    rule,
+                    channel.name,
+                    value,
+                    replacement
+                );
+                value = replacement;
+            }
+
+            value = self.hold(
+                settings,
+                &channel.name,
+                value,
+                current.as_deref(),
+                &reaction.properties,
+                now,
+            );
+
+            if value != proposed {
+                reaction
+                    .actions
+                    .insert(channel.name.clone(), value.clone());
+            }
+            self.played(&channel.name, &value, now);
+        }
+
+        let mut sequence = std::mem::take(&mut reaction.sequence);
+        sequence.retain(|action| {
+            let current = reaction
+                .actions
+                .get(&action.channel);
+            match self.reject(
+                settings,
+                &action.channel,
+                &action.value,
+                current.map(|value| value.as_str()),
+                &reaction.properties,
+                now,
+            ) {
+                | None => true,
+                | Some(rule) => {
+                    tracing::info!(
+                        "Policy {} dropped {} {} from sequence",
+                        rule,
+                        action.channel,
+                        action.value
+                    );
+                    false
+                },
+            }
+        });
+        for action in &sequence {
+            self.played_at.insert(
+                (
+                    action.channel.clone(),
+                    action.value.clone(),
+                ),
+                now,
+            );
+        }
+        reaction.sequence = sequence;
+    }
+
+    /// Name of the first rule rejecting the value, if any.
+    fn reject(
+        &self,
+        settings: &PolicySettings,
+        channel: &str,
+        value: &str,
+        current: Option<&str>,
+        properties: &BTreeMap<String, f64>,
+        now: Instant,
+    ) -> Option<&'static str> {
+        if settings
+            .forbidden_transitions
+            .iter()
+            .any(|transition| {
+                transition.channel == channel
+                    && transition.to == value
+                    && (transition.from.is_none()
+                        || transition.from.as_deref() == current)
+            })
+        {
+            return Some("forbidden transition");
+        }
+
+        if settings
+            .cooldowns
+            .iter()
+            .any(|cooldown| {
+                cooldown.channel == channel
+                    && cooldown.value == value
+                    && self
+                        .played_at
+                        .get(&(channel.to_string(), value.to_string()))
+                        .is_some_and(|at| {
+                            now.duration_since(*at)
+                                < Duration::from_secs(cooldown.seconds)
+                        })
+            })
+        {
+            return Some("cooldown");
+        }
+
+        if settings
+            .gates
+            .iter()
+            .any(|gate| {
+                gate.channel == channel
+                    && gate.value == value
+                    && properties
+                        .get(&gate.property)
+                        .is_some_and(|property| {
+                            gate.minimum
+                                .is_some_and(|minimum| *property < minimum)
+                                || gate
+                                    .maximum
+                                    .is_some_and(|maximum| *property > maximum)
+                        })
+            })
+        {
+            return Some("gate");
+        }
+
+        None
+    }
+
+    /// Keeps the current value until a new one has been proposed for the
+    /// turns of the hysteresis, unless the rules reject the current value.
+    fn hold(
+        &mut self,
+        settings: &PolicySettings,
+        channel: &str,
+        value: String,
+        current: Option<&str>,
+        properties: &BTreeMap<String, f64>,
+        now: Instant,
+    ) -> String {
+        let Some(hysteresis) = settings
+            .hysteresis
+            .iter()
+            .find(|hysteresis| hysteresis.channel == channel)
+        else {
+            return value;
+        };
+        let Some(current) = current else {
+            return value;
+        };
+        if current == value {
+            self.proposed.remove(channel);
+            return value;
+        }
+        // E.g. a gate closed since the current value was played.
+        if let Some(rule) = self.reject(
+            settings,
+            channel,
+            current,
+            Some(current),
+            properties,
+            now,
+        ) {
+            tracing::info!(
+                "Policy {} let {} {} go for {}",
+                rule,
+                channel,
+                current,
+                value
+            );
+            self.proposed.remove(channel);
+            return value;
+        }
+
+        let turns = match self.proposed.get(channel) {
+            | Some((proposed, turns)) if proposed == &value => turns + 1,
+            | _ => 1,
+        };
+        if turns >= hysteresis.turns {
+            self.proposed.remove(channel);
+            return value;
+        }
+
+        tracing::info!(
+            "Policy hysteresis kept {} {} over {} ({}/{})",
+            channel,
+            current,
+            value,
+            turns,
+            hysteresis.turns
+        );
+        self.proposed
+            .insert(channel.to_string(), (value, turns));
+
+        current.to_string()
+    }
+
+    fn played(
+        &mut self,
+        channel: &str,
+        value: &str,
+        now: Instant,
+    ) {
+        let previous = self
+            .current
+            .insert(channel.to_string(), value.to_string());
+        // A held value is not played again, so its cooldown keeps running.
+        if previous.as_deref() != Some(value) {
+            self.played_at.insert(
+                (channel.to_string(), value.to_string()),
+                now,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::creature::definition::tests::definition;
+
+    fn react(
+        state: &mut PolicyState,
+        definition: &CreatureDefinition,
+        motion: &str,
+        friendliness: f64,
+    ) -> String {
+        let mut reaction = Reaction::default();
+        reaction
+            .actions
+            .insert("motion".to_string(), motion.to_string());
+        reaction
+            .properties
+            .insert("friendliness".to_string(), friendliness);
+
+        state.apply(
+            &definition.policy,
+            definition,
+            &mut reaction,
+        );
+
+        reaction.actions["motion"].clone()
+    }
+
+    #[test]
+    fn forbidden_transition_is_replaced_by_the_fallback() {
+        let definition = definition(
+            r#"
+[[policy.forbidden_transitions]]
+channel = "motion"
+from = "MOTION_JUMP"
+to = "MOTION_DIE"
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_DIE",
+                0.0
+            ),
+            "MOTION_DIE"
+        );
+        react(
+            &mut state,
+            &definition,
+            "MOTION_JUMP",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_DIE",
+                0.0
+            ),
+            "MOTION_NEUTRAL"
+        );
+    }
+
+    #[test]
+    fn cooldown_rejects_a_value_played_recently() {
+        let definition = definition(
+            r#"
+[[policy.cooldowns]]
+channel = "motion"
+value = "MOTION_JUMP"
+seconds = 60
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_JUMP",
+                0.0
+            ),
+            "MOTION_JUMP"
+        );
+        react(
+            &mut state,
+            &definition,
+            "MOTION_EATING",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_JUMP",
+                0.0
+            ),
+            "MOTION_NEUTRAL"
+        );
+    }
+
+    #[test]
+    fn rejected_fallback_keeps_the_current_value() {
+        let definition = definition(
+            r#"
+[[policy.forbidden_transitions]]
+channel = "motion"
+to = "MOTION_DIE"
+
+[[policy.forbidden_transitions]]
+channel = "motion"
+from = "MOTION_EATING"
+to = "MOTION_NEUTRAL"
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        react(
+            &mut state,
+            &definition,
+            "MOTION_EATING",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_DIE",
+                0.0
+            ),
+            "MOTION_EATING"
+        );
+    }
+
+    #[test]
+    fn gate_allows_a_value_only_within_the_range() {
+        let definition = definition(
+            r#"
+[[policy.gates]]
+channel = "motion"
+value = "MOTION_ATTACK"
+property = "friendliness"
+maximum = 0.5
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_ATTACK",
+                0.8
+            ),
+            "MOTION_NEUTRAL"
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_ATTACK",
+                0.2
+            ),
+            "MOTION_ATTACK"
+        );
+    }
+
+    #[test]
+    fn hysteresis_changes_after_repeated_proposals() {
+        let definition = definition(
+            r#"
+[[policy.hysteresis]]
+channel = "motion"
+turns = 2
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        react(
+            &mut state,
+            &definition,
+            "MOTION_NEUTRAL",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_JUMP",
+                0.0
+            ),
+            "MOTION_NEUTRAL"
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_JUMP",
+                0.0
+            ),
+            "MOTION_JUMP"
+        );
+
+        // A different proposal starts counting again.
+        react(
+            &mut state,
+            &definition,
+            "MOTION_EATING",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_ATTACK",
+                0.0
+            ),
+            "MOTION_JUMP"
+        );
+    }
+
+    #[test]
+    fn hysteresis_does_not_hold_a_value_a_gate_rejects() {
+        let definition = definition(
+            r#"
+[[policy.hysteresis]]
+channel = "motion"
+turns = 3
+
+[[policy.gates]]
+channel = "motion"
+value = "MOTION_ATTACK"
+property = "friendliness"
+maximum = 0.5
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_ATTACK",
+                0.0
+            ),
+            "MOTION_ATTACK"
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_ATTACK",
+                0.8
+            ),
+            "MOTION_NEUTRAL"
+        );
+    }
+
+    #[test]
+    fn hysteresis_does_not_hold_a_value_in_cooldown() {
+        let definition = definition(
+            r#"
+[[policy.hysteresis]]
+channel = "motion"
+turns = 3
+
+[[policy.cooldowns]]
+channel = "motion"
+value = "MOTION_JUMP"
+seconds = 60
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        react(
+            &mut state,
+            &definition,
+            "MOTION_JUMP",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_EATING",
+                0.0
+            ),
+            "MOTION_EATING"
+        );
+    }
+
+    #[test]
+    fn hysteresis_does_not_hold_a_forbidden_repetition() {
+        let definition = definition(
+            r#"
+[[policy.hysteresis]]
+channel = "motion"
+turns = 3
+
+[[policy.forbidden_transitions]]
+channel = "motion"
+from = "MOTION_DIE"
+to = "MOTION_DIE"
+"#,
+        )
+        .unwrap();
+        let mut state = PolicyState::default();
+
+        react(
+            &mut state,
+            &definition,
+            "MOTION_DIE",
+            0.0,
+        );
+        assert_eq!(
+            react(
+                &mut state,
+                &definition,
+                "MOTION_JUMP",
+                0.0
+            ),
+            "MOTION_JUMP"
+        );
+    }
+
+    #[test]
+    fn idle_state_is_apart_from_an_author_of_the_creature_id() {
+        let definition = definition(
+            r#"
+[[policy.cooldowns]]
+channel = "motion"
+value = "MOTION_JUMP"
+seconds = 60
+"#,
+        )
+        .unwrap();
+        let mut policies = Policies::default();
+        let mut react = |key: PolicyKey| {
+            let mut reaction = Reaction::default();
+            reaction.actions.insert(
+                "motion".to_string(),
+                "MOTION_JUMP".to_string(),
+            );
+            policies.apply(
+                key,
+                &definition.policy,
+                &definition,
+                &mut reaction,
+            );
+            reaction.actions["motion"].clone()
+        };
+
+        assert_eq!(react(PolicyKey::Idle), "MOTION_JUMP");
+        assert_eq!(
+            react(PolicyKey::Author("test".to_string())),
+            "MOTION_JUMP"
+        );
+        assert_eq!(react(PolicyKey::Idle), "MOTION_NEUTRAL");
+    }
+
+    #[test]
+    fn prune_drops_stale_states() {
+        let definition = definition("").unwrap();
+        let mut policies = Policies::default();
+        for index in 0..PRUNE_THRESHOLD {
+            policies.states.insert(
+                PolicyKey::Author(index.to_string()),
+                PolicyState::default(),
+            );
+        }
+
+        policies.apply(
+            PolicyKey::Idle,
+            &definition.policy,
+            &definition,
+            &mut Reaction::default(),
+        );
+        policies.apply(
+            PolicyKey::Author("alice".to_string()),
+            &definition.policy,
+            &definition,
+            &mut Reaction::default(),
+        );
+
+        assert_eq!(policies.states.len(), 2);
+    }
+
+    #[test]
+    fn validate_rejects_unknown_values() {
+        assert!(definition(
+            r#"
+[[policy.cooldowns]]
+channel = "motion"
+value = "MOTION_FLY"
+seconds = 1
+"#,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{anyhow, Result};
+use minijinja::{Environment, UndefinedBehavior};
+use serde::Serialize;
+
+/// System message used when a definition has no template.
+pub(crate) const DEFAULT_TEMPLATE: &str = r#"{{ prompt }}
+{%- if personality %}
+Personality:
+{%- if personality.traits %}
+- Traits: {% for trait in personality.traits %}{{ trait.level }} {{ trait.name }}{% if not loop.last %}, {% endif %}{% endfor %}
+{%- endif %}
+{%- if personality.speech_style %}
+- Speech style: {{ personality.speech_style }}
+{%- endif %}
+{%- if personality.likes %}
+- Likes: {{ personality.likes | join(", ") }}
+{%- endif %}
+{%- if personality.dislikes %}
+- Dislikes: {{ personality.dislikes | join(", ") }}
+{%- endif %}
+{%- if personality.usual_mood %}
+- Usual mood: {{ personality.usual_mood }}
+{%- endif %}
+{%- endif %}
+Now: {{ weekday }} {{ date }} {{ time }}
+{%- if author %}
+You are talking with {{ author }}.
+{%- endif %}
+{%- if friendliness is not none %}
+Your friendliness toward {{ author }}: {{ friendliness | round(2) }}
+{%- endif %}
+{%- if mood %}
+Current mood: {% for emotion in mood %}{{ emotion.name }} {{ emotion.intensity | round(2) }}{% if not loop.last %}, {% endif %}{% endfor %}
+{%- endif %}
+{%- if needs %}
+Current needs: {% for need in needs %}{{ need.name }} {{ need.value | round(2) }}{% if need.description %} ({{ need.description }}){% endif %}{% if not loop.last %}, {% endif %}{% endfor %}
+{%- endif %}
+Related memories:
+{% for memory in memories -%}
+{{ "  - " }}{% if memory.kind == "event" %}[event] {% elif memory.kind == "reaction" %}[me] {% endif %}{{ memory.text }} ({% if memory.kind != "reaction" %}{{ memory.author }}, {% endif %}{{ memory.datetime }}, score: {{ memory.score | round(2) }})
+{% endfor %}"#;
+
+/// Variables available to system message templates.
+#[derive(Serialize, Debug, Clone, Default)]
+pub(crate) struct PromptContext {
+    /// Name of the creature.
+    pub(crate) name: String,
+    pub(crate) prompt: String,
+    /// Persona of the definition.
+    pub(crate) personality: Option<PersonalityContext>,
+    /// Local date, time and day of week, e.g. "2023-08-01", "14:05" and
+    /// "Tuesday".
+    pub(crate) date: String,
+    pub(crate) time: String,
+    pub(crate) weekday: String,
+    /// Who the creature reacts to, none on idle.
+    pub(crate) author: Option<String>,
+    /// Relationship toward the author.
+    pub(crate) friendliness: Option<f64>,
+    pub(crate) mood: Vec<EmotionContext>,
+    pub(crate) needs: Vec<NeedContext>,
+    pub(crate) memories: Vec<MemoryContext>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct PersonalityContext {
+    pub(crate) traits: Vec<TraitContext>,
+    pub(crate) speech_style: Option<String>,
+    pub(crate) likes: Vec<String>,
+    pub(crate) dislikes: Vec<String>,
+    /// Strongest emotion of the baseline mood.
+    pub(crate) usual_mood: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct TraitContext {
+    pub(crate) name: String,
+    /// "very low" to "very high".
+    pub(crate) level: String,
+    pub(crate) score: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct EmotionContext {
+    pub(crate) name: String,
+    pub(crate) intensity: f64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct NeedContext {
+    pub(crate) name: String,
+    pub(crate) value: f64,
+    pub(crate) description: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub(crate) struct MemoryContext {
+    pub(crate) text: String,
+    pub(crate) author: String,
+    /// Local time the memory was made, e.g. "2023-08-01 14:05".
+    pub(crate) datetime: String,
+    /// "speech", "event" or "reaction".
+    pub(crate) kind: String,
+    /// "private" or "public".
+    pub(crate) visibility: String,
+    pub(crate) score: f32,
+}
+
+impl PromptContext {
+    /// Context with the current local time.
+    pub(crate) fn now(
+        name: String,
+        prompt: String,
+    ) -> Self {
+        let now = chrono::Local::now();
+
+        Self {
+            name,
+            prompt,
+            date: now
+                .format("%Y-%m-%d")
+                .to_string(),
+            time: now
+                .format("%H:%M")
+                .to_string(),
+            weekday: now.format("%A").to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Context with every variable filled, to catch templates that refer to
+    /// unknown ones.
+    fn sample() -> Self {
+        let mut context = Self::now(
+            "creature".to_string(),
+            "prompt".to_string(),
+        );
+        context.personality = Some(PersonalityContext {
+            traits: vec![TraitContext {
+                name: "openness".to_string(),
+                level: "moderate".to_string(),
+                score: 0.5,
+            }],
+            speech_style: Some("speech style".to_string()),
+            likes: vec!["like".to_string()],
+            dislikes: vec!["dislike".to_string()],
+            usual_mood: Some("emotion".to_string()),
+        });
+        context.author = Some("author".to_string());
+        context.friendliness = Some(0.0);
+        context.mood = vec![EmotionContext {
+            name: "emotion".to_string(),
+            intensity: 0.5,
+        }];
+        context.needs = vec![NeedContext {
+            name: "need".to_string(),
+            value: 0.5,
+            description: Some("description".to_string()),
+        }];
+        context.memories = vec![MemoryContext {
+            text: "memory".to_string(),
+            author: "author".to_string(),
+            datetime: context.date.clone(),
+            kind: "speech".to_string(),
+            visibility: "private".to_string(),
+            score: 1.0,
+        }];
+
+        context
+    }
+}
+
+/// Functions of minijinja a template may call besides the variables.
+const BUILTIN_FUNCTIONS: [&str; 4] = [
+    "range",
+    "dict",
+    "debug",
+    "namespace",
+];
+
+/// Checks the syntax of a template and that it only uses known variables,
+/// including ones behind branches a sample render would skip.
+pub(crate) fn validate(template: &str) -> Result<()> {
+    let mut environment = Environment::new();
+    environment.add_template("prompt", template)?;
+
+    let known = match serde_json::to_value(PromptContext::default())? {
+        | serde_json::Value::Object(variables) => variables,
+        | _ => return Err(anyhow!("Prompt context is not a map")),
+    };
+    let mut unknown = environment
+        .get_template("prompt")?
+        .undeclared_variables(false)
+        .into_iter()
+        .filter(|name| {
+            !known.contains_key(name)
+                && !BUILTIN_FUNCTIONS.contains(&name.as_str())
+        })
+        .collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        unknown.sort();
+        return Err(anyhow!(
+            "Unknown template variables: {}",
+            unknown.join(", ")
+        ));
+    }
+
+    // Catches unknown fields of known variables as well.
+    render(template, &PromptContext::sample())?;
+
+    Ok(())
+}
+
+pub(crate) fn render(
+    template: &str,
+    context: &PromptContext,
+) -> Result<String> {
+    let mut environment = Environment::new();
+    environment.set_undefined_behavior(UndefinedBehavior::Strict);
+
+    let rendered = environment
+        .render_str(template, context)
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to render prompt template: {:?}",
+                error
+            );
+            error
+        })?;
+
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_default_template() {
+        assert!(validate(DEFAULT_TEMPLATE).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_variable_in_untaken_branch() {
+        let template = "{% if false %}{{ unknown }}{% endif %}";
+
+        assert!(validate(template).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_field() {
+        assert!(validate(
+            "{% for memory in memories %}{{ memory.unknown }}{% endfor %}"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn accepts_loop_variables_and_functions() {
+        let template = "{% for i in range(2) %}{{ loop.index }}{{ i }}{% endfor %}{% set x = name %}{{ x }}";
+
+        assert!(validate(template).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_syntax() {
+        assert!(validate("{{ name").is_err());
+    }
+}
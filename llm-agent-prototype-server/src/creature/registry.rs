@@ -0,0 +1,523 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::Mutex;
+
+use crate::chat_gpt_api::memory::FiniteQueueMemory;
+use crate::config::CreaturesConfig;
+use crate::creature::affect::Affect;
+use crate::creature::definition::CreatureDefinition;
+use crate::creature::needs::Needs;
+use crate::creature::policy::Policies;
+use crate::creature::relationship::Relationships;
+use crate::rpc_context::RpcContext;
+use crate::vector_db::database::{DataBase, INDEXED_FIELDS};
+use crate::vector_db::embeddings::Embedder;
+use crate::vector_db::store::{Connection, VectorStoreConfig};
+
+/// Id of the creature used when a client names none.
+pub(crate) const DEFAULT_CREATURE_ID: &str = "default";
+
+/// Every creature hosted by the server, keyed by id.
+#[derive(Debug)]
+pub(crate) struct CreatureRegistry {
+    creatures: HashMap<String, Arc<Mutex<RpcContext>>>,
+    /// Database of each collection, keyed by its configured name.
+    databases: HashMap<String, DataBase>,
+    config: CreaturesConfig,
+    /// Creature id and modification time of each definition file, the id
+    /// being none for files added after start.
+    files: Mutex<HashMap<PathBuf, (Option<String>, SystemTime)>>,
+}
+
+impl CreatureRegistry {
+    /// Loads every `.toml` and `.json` definition in the directory.
+    #[tracing::instrument(
+        name = "creature.registry.load",
+        err,
+        skip(config, connection, store_config, embeddings),
+        fields(directory = %config.directory)
+    )]
+    pub(crate) async fn load(
+        config: CreaturesConfig,
+        connection: Connection,
+        store_config: VectorStoreConfig,
+        embeddings: Arc<dyn Embedder>,
+    ) -> Result<Self> {
+        let mut definitions = Vec::new();
+        let mut files = HashMap::new();
+        for path in definition_paths(Path::new(&config.directory))? {
+            let modified = modified_at(&path)?;
+            let definition = CreatureDefinition::load(&path, &config)?;
+            files.insert(
+                path,
+                (Some(definition.id.clone()), modified),
+            );
+            definitions.push(definition);
+        }
+        if definitions.is_empty() {
+            return Err(anyhow!("No creature definitions found"));
+        }
+
+        // Open each collection once even if it is shared.
+        let mut databases: HashMap<String, DataBase> = HashMap::new();
+        for definition in &definitions {
+            if let Entry::Vacant(entry) =
+                databases.entry(collection_of(definition, &config))
+            {
+                let store = connection
+                    .open(
+                        entry.key(),
+                        embeddings.as_ref(),
+                        &store_config,
+                        &INDEXED_FIELDS,
+                    )
+                    .await?;
+                entry.insert(DataBase::new(store, embeddings.clone()));
+            }
+        }
+
+        let mut creatures = HashMap::new();
+        for definition in definitions {
+            let database = &databases[&collection_of(&definition, &config)];
+            let namespace = match definition.memory.collection {
+                | Some(_) => None,
+                | None => Some(definition.id.clone()),
+            };
+            let context = RpcContext {
+                context_memory: FiniteQueueMemory::new(
+                    definition.memory.context_size,
+                ),
+                long_memory: database.with_namespace(namespace),
+                affect: definition
+                    .affect
+                    .as_ref()
+                    .map(Affect::new),
+                relationships: load_relationships(&definition)?,
+                policies: Policies::default(),
+                needs: load_needs(&definition)?,
+                definition,
+            };
+
+            let id = context.definition.id.clone();
+            if creatures
+                .insert(
+                    id.clone(),
+                    Arc::new(Mutex::new(context)),
+                )
+                .is_some()
+            {
+                return Err(anyhow!(
+                    "Duplicated creature id: {}",
+                    id
+                ));
+            }
+        }
+
+        tracing::info!("Loaded {} creatures", creatures.len());
+
+        Ok(Self {
+            creatures,
+            databases,
+            config,
+            files: Mutex::new(files),
+        })
+    }
+
+    /// Releases the collections, deleting ephemeral ones.
+    pub(crate) async fn close(&self) {
+        for database in self.databases.values() {
+            if let Err(error) = database.close().await {
+                tracing::error!(
+                    "Failed to close collection {}: {:?}",
+                    database.store.name(),
+                    error
+                );
+            }
+        }
+    }
+
+    /// Polls the definition files and swaps edited ones into the running
+    /// creatures.
+    pub(crate) async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(
+            self.config
+                .reload_interval_seconds,
+        ));
+        loop {
+            interval.tick().await;
+            self.reload_modified().await;
+        }
+    }
+
+    async fn reload_modified(&self) {
+        let paths = match self.scan().await {
+            | Ok(paths) => paths,
+            | Err(error) => {
+                tracing::error!(
+                    "Failed to watch creature directory: {:?}",
+                    error
+                );
+                return;
+            },
+        };
+
+        let mut files = self.files.lock().await;
+        for (path, modified) in paths {
+            let id = match files.get(&path) {
+                | Some((_, at)) if *at == modified => continue,
+                | Some((id, _)) => id.clone(),
+                | None => None,
+            };
+            files.insert(path.clone(), (id.clone(), modified));
+
+            let Some(id) = id else {
+                tracing::warn!(
+                    "Ignored new creature definition {} until restart",
+                    path.display()
+                );
+                continue;
+            };
+
+            match self.reload(&id, &path).await {
+                | Ok(()) => tracing::info!(
+                    "Reloaded creature {} from {}",
+                    id,
+                    path.display()
+                ),
+                | Err(error) => tracing::error!(
+                    "Rejected edit of creature {} in {}: {:?}",
+                    id,
+                    path.display(),
+                    error
+                ),
+            }
+        }
+    }
+
+    /// Definition files with their modification times, read off the
+    /// runtime threads.
+    async fn scan(&self) -> Result<Vec<(PathBuf, SystemTime)>> {
+        let directory = PathBuf::from(&self.config.directory);
+        tokio::task::spawn_blocking(move || {
+            Ok(definition_paths(&directory)?
+                .into_iter()
+                .filter_map(|path| {
+                    let modified = modified_at(&path).ok()?;
+                    Some((path, modified))
+                })
+                .collect())
+        })
+        .await?
+    }
+
+    /// Replaces the definition of a creature while keeping its
+    /// conversation and feelings. Nothing changes when the new definition
+    /// is invalid.
+    async fn reload(
+        &self,
+        id: &str,
+        path: &Path,
+    ) -> Result<()> {
+        let context = self
+            .creatures
+            .get(id)
+            .ok_or_else(|| anyhow!("Creature not found: {}", id))?;
+        let relationships_path = context
+            .lock()
+            .await
+            .relationships
+            .as_ref()
+            .map(|relationships| {
+                relationships
+                    .path()
+                    .to_path_buf()
+            });
+
+        // Files are read before locking the creature so that its turns
+        // do not wait for the disk.
+        let path = path.to_path_buf();
+        let config = self.config.clone();
+        let (definition, relationships, needs) =
+            tokio::task::spawn_blocking(move || {
+                let definition = CreatureDefinition::load(&path, &config)?;
+                // Values in memory are kept unless they are persisted
+                // elsewhere now.
+                let relationships = match &definition.relationship {
+                    | Some(settings)
+                        if Some(settings.path_of(&definition.id))
+                            != relationships_path =>
+                    {
+                        load_relationships(&definition)?
+                    },
+                    | _ => None,
+                };
+                let needs = load_needs(&definition)?;
+                anyhow::Ok((definition, relationships, needs))
+            })
+            .await??;
+        if definition.id != id {
+            return Err(anyhow!(
+                "Creature id changed from {} to {}, which needs a restart",
+                id,
+                definition.id
+            ));
+        }
+
+        let mut context = context.lock().await;
+
+        if definition.memory.collection
+            != context
+                .definition
+                .memory
+                .collection
+        {
+            return Err(anyhow!(
+                "Memory collection of creature {} changed, which needs a restart",
+                id
+            ));
+        }
+
+        // Intensities of another channel mean nothing to the new one.
+        let is_same_channel = match (
+            &definition.affect,
+            &context.definition.affect,
+        ) {
+            | (Some(new), Some(old)) => new.channel == old.channel,
+            | _ => false,
+        };
+        context.affect = match (
+            &definition.affect,
+            context.affect.take(),
+        ) {
+            | (None, _) => None,
+            | (Some(_), Some(affect)) if is_same_channel => Some(affect),
+            | (Some(settings), _) => Some(Affect::new(settings)),
+        };
+        context.relationships = match (&definition.relationship, relationships)
+        {
+            | (None, _) => None,
+            | (Some(_), Some(relationships)) => Some(relationships),
+            | (Some(_), None) => context.relationships.take(),
+        };
+        context.needs = needs;
+        context
+            .context_memory
+            .resize(definition.memory.context_size);
+        context.definition = definition;
+
+        Ok(())
+    }
+
+    /// Looks up a creature by id, falling back to the default creature or
+    /// to the only one hosted.
+    pub(crate) fn get(
+        &self,
+        id: Option<&str>,
+    ) -> Option<Arc<Mutex<RpcContext>>> {
+        match id {
+            | Some(id) => self
+                .creatures
+                .get(id)
+                .cloned(),
+            | None => self
+                .creatures
+                .get(DEFAULT_CREATURE_ID)
+                .or_else(|| {
+                    if self.creatures.len() == 1 {
+                        self.creatures.values().next()
+                    } else {
+                        None
+                    }
+                })
+                .cloned(),
+        }
+    }
+}
+
+fn definition_paths(directory: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = std::fs::read_dir(directory)
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to read creature directory: {:?}",
+                error
+            );
+            error
+        })?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|path| {
+        matches!(
+            path.extension()
+                .and_then(|extension| extension.to_str()),
+            Some("toml") | Some("json")
+        )
+    });
+    paths.sort();
+
+    Ok(paths)
+}
+
+fn modified_at(path: &Path) -> Result<SystemTime> {
+    Ok(std::fs::metadata(path)?.modified()?)
+}
+
+fn load_relationships(
+    definition: &CreatureDefinition
+) -> Result<Option<Relationships>> {
+    definition
+        .relationship
+        .as_ref()
+        .map(|settings| Relationships::load(&definition.id, settings))
+        .transpose()
+}
+
+fn load_needs(definition: &CreatureDefinition) -> Result<Option<Needs>> {
+    definition
+        .needs
+        .as_ref()
+        .map(|settings| Needs::load(&definition.id, settings))
+        .transpose()
+}
+
+/// Own collection of the creature or the shared one.
+fn collection_of(
+    definition: &CreatureDefinition,
+    config: &CreaturesConfig,
+) -> String {
+    definition
+        .memory
+        .collection
+        .clone()
+        .unwrap_or_else(|| {
+            config
+                .shared_collection
+                .clone()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vector_db::embeddings::hashing::HashingEmbedder;
+
+    const CHANNELS: &str = r#"
+[[channels]]
+name = "emotion"
+values = [{ name = "EMOTION_NEUTRAL" }, { name = "EMOTION_HAPPY" }]
+"#;
+
+    async fn registry(directory: &Path) -> CreatureRegistry {
+        std::fs::create_dir_all(directory).unwrap();
+        std::fs::write(
+            directory.join("default.toml"),
+            format!("prompt = \"First.\"\n{}", CHANNELS),
+        )
+        .unwrap();
+
+        CreatureRegistry::load(
+            CreaturesConfig {
+                directory: directory
+                    .to_string_lossy()
+                    .to_string(),
+                ..Default::default()
+            },
+            Connection::Memory,
+            VectorStoreConfig::default(),
+            Arc::new(HashingEmbedder::new(8)),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Writes the definition and forgets when the file was last seen, as
+    /// modification times may not change within a test.
+    async fn edit(
+        registry: &CreatureRegistry,
+        directory: &Path,
+        text: &str,
+    ) {
+        let path = directory.join("default.toml");
+        std::fs::write(&path, text).unwrap();
+        registry
+            .files
+            .lock()
+            .await
+            .get_mut(&path)
+            .unwrap()
+            .1 = SystemTime::UNIX_EPOCH;
+    }
+
+    async fn prompt(registry: &CreatureRegistry) -> String {
+        registry
+            .get(None)
+            .unwrap()
+            .lock()
+            .await
+            .definition
+            .prompt
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn valid_edit_takes_effect() {
+        let directory = std::env::temp_dir().join(format!(
+            "creatures_{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let registry = registry(&directory).await;
+
+        edit(
+            &registry,
+            &directory,
+            &format!("prompt = \"Second.\"\n{}", CHANNELS),
+        )
+        .await;
+        registry
+            .reload_modified()
+            .await;
+
+        assert_eq!(prompt(&registry).await, "Second.");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn invalid_edit_keeps_the_old_definition() {
+        let directory = std::env::temp_dir().join(format!(
+            "creatures_{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let registry = registry(&directory).await;
+
+        edit(
+            &registry,
+            &directory,
+            &format!(
+                "prompt = \"Second.\"\nid = \"other\"\n{}",
+                CHANNELS
+            ),
+        )
+        .await;
+        registry
+            .reload_modified()
+            .await;
+        assert_eq!(prompt(&registry).await, "First.");
+
+        edit(
+            &registry,
+            &directory,
+            "prompt = \"Second.\"\nchannels = []\n",
+        )
+        .await;
+        registry
+            .reload_modified()
+            .await;
+        assert_eq!(prompt(&registry).await, "First.");
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
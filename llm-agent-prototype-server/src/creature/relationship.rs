@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::creature::definition::NumericProperty;
+use crate::creature::storage::{load_json, save_json};
+
+/// How the server keeps a numeric property per author, e.g. friendliness,
+/// from bounded changes proposed by the LLM.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RelationshipSettings {
+    /// Numeric property owned by the server.
+    #[serde(default = "default_property")]
+    pub(crate) property: String,
+    /// Largest change the LLM may propose in one turn.
+    #[serde(default = "default_max_delta")]
+    pub(crate) max_delta: f64,
+    /// Share of the proposed change applied in one turn.
+    #[serde(default = "default_smoothing")]
+    pub(crate) smoothing: f64,
+    /// Value for an author met for the first time.
+    #[serde(default)]
+    pub(crate) initial: f64,
+    /// JSON file the values are persisted to. Defaults to
+    /// `relationships/<creature id>.json`.
+    #[serde(default)]
+    pub(crate) path: Option<PathBuf>,
+}
+
+fn default_property() -> String {
+    "friendliness".to_string()
+}
+
+fn default_max_delta() -> f64 {
+    0.1
+}
+
+fn default_smoothing() -> f64 {
+    0.5
+}
+
+/// Values of the property per author of one creature.
+#[derive(Debug)]
+pub(crate) struct Relationships {
+    path: PathBuf,
+    values: HashMap<String, f64>,
+}
+
+impl RelationshipSettings {
+    /// File the values of the creature are persisted to.
+    pub(crate) fn path_of(
+        &self,
+        creature_id: &str,
+    ) -> PathBuf {
+        self.path
+            .clone()
+            .unwrap_or_else(|| {
+                Path::new("relationships").join(format!("{}.json", creature_id))
+            })
+    }
+
+    /// Name of the change in the reaction function arguments.
+    pub(crate) fn delta_key(&self) -> String {
+        format!("{}_delta", self.property)
+    }
+
+    pub(crate) fn schema(
+        &self,
+        property: &NumericProperty,
+    ) -> Value {
+        let mut description = format!(
+            "Change of your {} toward the speaker in this turn. It changes slowly.",
+            self.property
+        );
+        if let Some(meaning) = &property.description {
+            description = format!("{}\n{}", description, meaning);
+        }
+
+        json!({
+            "type": "number",
+            "description": description,
+            "minimum": -self.max_delta,
+            "maximum": self.max_delta,
+        })
+    }
+}
+
+impl Relationships {
+    #[tracing::instrument(
+        name = "creature.relationship.load",
+        err,
+        skip(creature_id, settings)
+    )]
+    pub(crate) fn load(
+        creature_id: &str,
+        settings: &RelationshipSettings,
+    ) -> Result<Self> {
+        let path = settings.path_of(creature_id);
+
+        let values: HashMap<String, f64> = load_json(&path)?;
+
+        tracing::info!(
+            "Loaded {} relationships of creature {} from {}",
+            values.len(),
+            creature_id,
+            path.display()
+        );
+
+        Ok(Self {
+            path,
+            values,
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Value toward the author.
+    pub(crate) fn get(
+        &self,
+        settings: &RelationshipSettings,
+        author: &str,
+    ) -> f64 {
+        self.values
+            .get(author)
+            .copied()
+            .unwrap_or(settings.initial)
+    }
+
+    /// Applies the proposed change within the range of the property and
+    /// persists the result.
+    pub(crate) async fn update(
+        &mut self,
+        settings: &RelationshipSettings,
+        property: &NumericProperty,
+        author: &str,
+        delta: f64,
+    ) -> Result<f64> {
+        let current = self.get(settings, author);
+        let delta = delta.clamp(-settings.max_delta, settings.max_delta);
+        let value = (current + settings.smoothing * delta)
+            .clamp(property.minimum, property.maximum);
+
+        tracing::info!(
+            "Changed {} toward {} from {} to {}",
+            settings.property,
+            author,
+            current,
+            value
+        );
+
+        self.values
+            .insert(author.to_string(), value);
+        save_json(&self.path, &self.values).await?;
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(path: PathBuf) -> RelationshipSettings {
+        RelationshipSettings {
+            property: default_property(),
+            max_delta: 0.1,
+            smoothing: 0.5,
+            initial: 0.0,
+            path: Some(path),
+        }
+    }
+
+    fn property(
+        minimum: f64,
+        maximum: f64,
+    ) -> NumericProperty {
+        NumericProperty {
+            name: default_property(),
+            description: None,
+            minimum,
+            maximum,
+        }
+    }
+
+    fn temporary_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "relationships_{}.json",
+            uuid::Uuid::new_v4().simple()
+        ))
+    }
+
+    #[tokio::test]
+    async fn update_smooths_the_change() {
+        let path = temporary_path();
+        let settings = settings(path.clone());
+        let mut relationships = Relationships::load("test", &settings).unwrap();
+
+        let value = relationships
+            .update(
+                &settings,
+                &property(-1.0, 1.0),
+                "alice",
+                0.1,
+            )
+            .await
+            .unwrap();
+
+        assert!((value - 0.05).abs() < 1e-9);
+        assert_eq!(
+            relationships.get(&settings, "alice"),
+            value
+        );
+        assert_eq!(relationships.get(&settings, "bob"), 0.0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_clamps_the_change_to_max_delta() {
+        let path = temporary_path();
+        let settings = settings(path.clone());
+        let mut relationships = Relationships::load("test", &settings).unwrap();
+
+        let value = relationships
+            .update(
+                &settings,
+                &property(-1.0, 1.0),
+                "alice",
+                -5.0,
+            )
+            .await
+            .unwrap();
+
+        assert!((value + 0.05).abs() < 1e-9);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_clamps_the_value_to_the_range() {
+        let path = temporary_path();
+        let settings = settings(path.clone());
+        let mut relationships = Relationships::load("test", &settings).unwrap();
+        let property = property(0.0, 0.12);
+
+        for _ in 0..5 {
+            relationships
+                .update(&settings, &property, "alice", 0.1)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            relationships.get(&settings, "alice"),
+            0.12
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_reads_saved_values() {
+        let path = temporary_path();
+        let settings = settings(path.clone());
+        let mut relationships = Relationships::load("test", &settings).unwrap();
+        relationships
+            .update(
+                &settings,
+                &property(-1.0, 1.0),
+                "alice",
+                0.1,
+            )
+            .await
+            .unwrap();
+
+        let loaded = Relationships::load("test", &settings).unwrap();
+
+        assert!((loaded.get(&settings, "alice") - 0.05).abs() < 1e-9);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
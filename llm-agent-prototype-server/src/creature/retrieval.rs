@@ -0,0 +1,472 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::vector_db::database::{
+    Record, RecordKind, Visibility, AUTHOR_KEY, TIMESTAMP_KEY, VISIBILITY_KEY,
+};
+use crate::vector_db::store::{
+    cosine_similarity, Condition, Filter, ScoredPoint,
+};
+
+/// Whose memories a creature recalls.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MemoryScope {
+    /// Only memories of whom it reacts to.
+    Author,
+    /// Memories of whom it reacts to and public ones of anyone else.
+    #[default]
+    AuthorAndPublic,
+    /// Every memory regardless of its author.
+    All,
+}
+
+/// Which long-term memories are searched for a stimulus and how they are
+/// ranked, scoring each by a weighted mean of its similarity to the
+/// stimulus, its recency and its importance.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RetrievalSettings {
+    #[serde(default)]
+    pub(crate) scope: MemoryScope,
+    /// Only memories of the last hours when given. Memories written before
+    /// timestamps existed fall outside every window.
+    #[serde(default)]
+    pub(crate) window_hours: Option<f64>,
+    #[serde(default = "default_weight")]
+    pub(crate) relevance_weight: f64,
+    #[serde(default = "default_weight")]
+    pub(crate) recency_weight: f64,
+    #[serde(default = "default_weight")]
+    pub(crate) importance_weight: f64,
+    /// Hours after which the recency of a memory halves.
+    #[serde(default = "default_recency_half_life_hours")]
+    pub(crate) recency_half_life_hours: f64,
+    /// Memories scoring below are never recalled.
+    #[serde(default)]
+    pub(crate) min_score: f64,
+    /// Memories less similar to the stimulus are never recalled, however
+    /// recent or important they are.
+    #[serde(default)]
+    pub(crate) min_relevance: f64,
+    /// Re-ranks by maximal marginal relevance when given, trading the score
+    /// at 1 for difference from the memories already picked at 0.
+    #[serde(default)]
+    pub(crate) mmr_lambda: Option<f64>,
+    /// Candidates searched per recalled memory before ranking.
+    #[serde(default = "default_candidate_factor")]
+    pub(crate) candidate_factor: u64,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+fn default_recency_half_life_hours() -> f64 {
+    24.0
+}
+
+fn default_candidate_factor() -> u64 {
+    4
+}
+
+impl Default for RetrievalSettings {
+    fn default() -> Self {
+        Self {
+            scope: MemoryScope::default(),
+            window_hours: None,
+            relevance_weight: default_weight(),
+            recency_weight: default_weight(),
+            importance_weight: default_weight(),
+            recency_half_life_hours: default_recency_half_life_hours(),
+            min_score: 0.0,
+            min_relevance: 0.0,
+            mmr_lambda: None,
+            candidate_factor: default_candidate_factor(),
+        }
+    }
+}
+
+impl RetrievalSettings {
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self
+            .window_hours
+            .is_some_and(|hours| !(hours.is_finite() && hours > 0.0))
+        {
+            return Err(anyhow!("window_hours must be positive"));
+        }
+        let weights = [
+            self.relevance_weight,
+            self.recency_weight,
+            self.importance_weight,
+        ];
+        if weights
+            .iter()
+            .any(|weight| !(weight.is_finite() && *weight >= 0.0))
+            || weights.iter().sum::<f64>() <= 0.0
+        {
+            return Err(anyhow!(
+                "Weights must not be negative and must not all be zero"
+            ));
+        }
+        if !(self
+            .recency_half_life_hours
+            .is_finite()
+            && self.recency_half_life_hours > 0.0)
+        {
+            return Err(anyhow!(
+                "recency_half_life_hours must be positive"
+            ));
+        }
+        // Ranges reject NaN and infinities as well.
+        if !(0.0..=1.0).contains(&self.min_score) {
+            return Err(anyhow!("min_score must be in [0, 1]"));
+        }
+        if !(0.0..=1.0).contains(&self.min_relevance) {
+            return Err(anyhow!(
+                "min_relevance must be in [0, 1]"
+            ));
+        }
+        if self
+            .mmr_lambda
+            .is_some_and(|lambda| !(0.0..=1.0).contains(&lambda))
+        {
+            return Err(anyhow!("mmr_lambda must be in [0, 1]"));
+        }
+        if self.candidate_factor == 0 {
+            return Err(anyhow!(
+                "candidate_factor must be positive"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Number of candidates to search for the limit of recalled memories.
+    pub(crate) fn candidates(
+        &self,
+        limit: u64,
+    ) -> u64 {
+        limit * self.candidate_factor
+    }
+
+    /// Up to `limit` memories of the candidates best first, with their
+    /// scores.
+    pub(crate) fn rank(
+        &self,
+        candidates: Vec<ScoredPoint>,
+        limit: usize,
+        now: DateTime<Utc>,
+    ) -> Vec<(Record, f64)> {
+        let mut scored = Vec::new();
+        for point in candidates {
+            let record = match Record::from_payload(&point.payload) {
+                | Ok(record) => record,
+                | Err(error) => {
+                    tracing::warn!("Skipped broken memory: {:?}", error);
+                    continue;
+                },
+            };
+            let similarity = point.score as f64;
+            if similarity < self.min_relevance {
+                continue;
+            }
+            let score = self.score(&record, similarity, now);
+            if score >= self.min_score {
+                scored.push((record, score, point.vector));
+            }
+        }
+
+        match self.mmr_lambda {
+            | Some(lambda) => select_diverse(scored, limit, lambda),
+            | None => {
+                scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+                scored
+                    .into_iter()
+                    .take(limit)
+                    .map(|(record, score, _)| (record, score))
+                    .collect()
+            },
+        }
+    }
+
+    fn score(
+        &self,
+        record: &Record,
+        similarity: f64,
+        now: DateTime<Utc>,
+    ) -> f64 {
+        let age_hours = (now - record.datetime)
+            .num_seconds()
+            .max(0) as f64
+            / 3600.0;
+        let recency = 0.5_f64.powf(age_hours / self.recency_half_life_hours);
+
+        (self.relevance_weight * similarity.clamp(0.0, 1.0)
+            + self.recency_weight * recency
+            + self.importance_weight
+                * record
+                    .importance
+                    .clamp(0.0, 1.0))
+            / (self.relevance_weight
+                + self.recency_weight
+                + self.importance_weight)
+    }
+
+    /// Filter of the memories to recall when reacting to the author, none
+    /// meaning an idle action where only public memories are recalled
+    /// unless the scope is all.
+    pub(crate) fn filter(
+        &self,
+        author: Option<&str>,
+        now: DateTime<Utc>,
+    ) -> Filter {
+        let mut filter = Filter::default();
+
+        let public = Condition::equals(
+            VISIBILITY_KEY,
+            Visibility::Public.parse_to_string(),
+        );
+        match (self.scope, author) {
+            | (MemoryScope::All, _) => {},
+            | (MemoryScope::Author, Some(author)) => {
+                filter
+                    .must
+                    .push(Condition::equals(
+                        AUTHOR_KEY,
+                        author.to_string(),
+                    ))
+            },
+            | (MemoryScope::AuthorAndPublic, Some(author)) => {
+                filter.should = vec![
+                    Condition::equals(AUTHOR_KEY, author.to_string()),
+                    public,
+                ];
+            },
+            | (_, None) => filter.must.push(public),
+        }
+
+        // A window reaching before any representable time is no bound.
+        let since = self
+            .window_hours
+            .and_then(|hours| {
+                std::time::Duration::try_from_secs_f64(hours * 3600.0).ok()
+            })
+            .and_then(|window| Duration::from_std(window).ok())
+            .and_then(|window| now.checked_sub_signed(window));
+        if let Some(since) = since {
+            filter
+                .must
+                .push(Condition::range(
+                    TIMESTAMP_KEY,
+                    Some(since.timestamp() as f64),
+                    None,
+                ));
+        }
+
+        filter
+    }
+}
+
+/// Greedily picks the memory with the best trade-off between its score and
+/// its similarity to the ones already picked.
+fn select_diverse(
+    mut candidates: Vec<(Record, f64, Vec<f32>)>,
+    limit: usize,
+    lambda: f64,
+) -> Vec<(Record, f64)> {
+    let mut selected: Vec<(Record, f64, Vec<f32>)> = Vec::new();
+    while selected.len() < limit && !candidates.is_empty() {
+        let marginal = |(_, score, vector): &(Record, f64, Vec<f32>)| {
+            let redundancy = selected
+                .iter()
+                .map(|(_, _, picked)| cosine_similarity(vector, picked) as f64)
+                .fold(0.0, f64::max);
+            lambda * score - (1.0 - lambda) * redundancy
+        };
+        let best = candidates
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| marginal(a).total_cmp(&marginal(b)))
+            .map(|(index, _)| index)
+            .unwrap_or_default();
+        selected.push(candidates.swap_remove(best));
+    }
+
+    selected
+        .into_iter()
+        .map(|(record, score, _)| (record, score))
+        .collect()
+}
+
+/// Heuristic importance of a memory in [0, 1] rated when it is written:
+/// events matter more than speech, and so do exclamations, questions and
+/// longer texts.
+pub(crate) fn rate_importance(
+    text: &str,
+    kind: RecordKind,
+) -> f64 {
+    let mut importance = match kind {
+        | RecordKind::Speech => 0.3,
+        | RecordKind::Event => 0.5,
+        | RecordKind::Reaction => 0.4,
+    };
+    if text.contains('!') || text.contains('！') {
+        importance += 0.2;
+    }
+    if text.contains('?') || text.contains('？') {
+        importance += 0.1;
+    }
+    importance += (text.chars().count() as f64 / 100.0).min(1.0) * 0.2;
+
+    importance.min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_rejects_windows_that_are_not_positive_numbers() {
+        for hours in [
+            0.0,
+            -1.0,
+            f64::NAN,
+            f64::INFINITY,
+        ] {
+            let settings = RetrievalSettings {
+                window_hours: Some(hours),
+                ..Default::default()
+            };
+
+            assert!(
+                settings.validate().is_err(),
+                "{}",
+                hours
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_values_that_are_not_finite() {
+        let settings = [
+            RetrievalSettings {
+                relevance_weight: f64::NAN,
+                ..Default::default()
+            },
+            RetrievalSettings {
+                recency_weight: f64::INFINITY,
+                ..Default::default()
+            },
+            RetrievalSettings {
+                recency_half_life_hours: f64::NAN,
+                ..Default::default()
+            },
+            RetrievalSettings {
+                recency_half_life_hours: f64::INFINITY,
+                ..Default::default()
+            },
+            RetrievalSettings {
+                min_score: f64::NAN,
+                ..Default::default()
+            },
+            RetrievalSettings {
+                min_relevance: f64::NAN,
+                ..Default::default()
+            },
+        ];
+
+        for settings in settings {
+            assert!(
+                settings.validate().is_err(),
+                "{:?}",
+                settings
+            );
+        }
+        assert!(RetrievalSettings::default()
+            .validate()
+            .is_ok());
+    }
+
+    fn candidate(
+        text: &str,
+        similarity: f32,
+        importance: f64,
+    ) -> ScoredPoint {
+        ScoredPoint {
+            vector: vec![1.0],
+            score: similarity,
+            payload: Record::new(
+                text.to_string(),
+                "alice".to_string(),
+                RecordKind::Speech,
+                Visibility::Public,
+                importance,
+            )
+            .to_payload(),
+        }
+    }
+
+    #[test]
+    fn rank_drops_memories_below_the_relevance_floor() {
+        let settings = RetrievalSettings {
+            min_relevance: 0.5,
+            ..Default::default()
+        };
+
+        let ranked = settings.rank(
+            vec![
+                candidate("recent and important", 0.1, 1.0),
+                candidate("on topic", 0.8, 0.0),
+            ],
+            10,
+            Utc::now(),
+        );
+
+        let texts = ranked
+            .iter()
+            .map(|(record, _)| record.text.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["on topic"]);
+    }
+
+    #[test]
+    fn filter_bounds_the_timestamp_by_the_window() {
+        let now = Utc::now();
+        let settings = RetrievalSettings {
+            scope: MemoryScope::All,
+            window_hours: Some(2.0),
+            ..Default::default()
+        };
+
+        let filter = settings.filter(None, now);
+
+        match filter.must.as_slice() {
+            | [Condition::Range {
+                gte: Some(since),
+                lte: None,
+                ..
+            }] => {
+                assert_eq!(
+                    *since,
+                    (now - Duration::hours(2)).timestamp() as f64
+                )
+            },
+            | conditions => panic!("Unexpected conditions {:?}", conditions),
+        }
+    }
+
+    #[test]
+    fn filter_ignores_windows_beyond_representable_time() {
+        let settings = RetrievalSettings {
+            scope: MemoryScope::All,
+            window_hours: Some(1e300),
+            ..Default::default()
+        };
+
+        assert!(settings
+            .filter(None, Utc::now())
+            .must
+            .is_empty());
+    }
+}
@@ -0,0 +1,321 @@
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+
+use crate::creature::definition::ActionChannel;
+
+/// Name of the sequence in the reaction function arguments.
+pub(crate) const SEQUENCE_KEY: &str = "sequence";
+
+/// Limits of the ordered actions a creature may play in one turn.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct SequenceSettings {
+    #[serde(default = "default_max_length")]
+    pub(crate) max_length: usize,
+    /// Every action must end within this many milliseconds from the start
+    /// of the turn.
+    #[serde(default = "default_max_total_duration_ms")]
+    pub(crate) max_total_duration_ms: u32,
+    /// Loop counts above are clamped.
+    #[serde(default = "default_max_loop_count")]
+    pub(crate) max_loop_count: u32,
+    /// Assumed length of one loop when checking when a looped action ends.
+    #[serde(default = "default_loop_duration_ms")]
+    pub(crate) loop_duration_ms: u32,
+}
+
+fn default_max_length() -> usize {
+    8
+}
+
+fn default_max_total_duration_ms() -> u32 {
+    10000
+}
+
+fn default_max_loop_count() -> u32 {
+    4
+}
+
+fn default_loop_duration_ms() -> u32 {
+    1000
+}
+
+/// An action played at an offset from the start of the turn.
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TimedAction {
+    pub(crate) channel: String,
+    pub(crate) value: String,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_integral"
+    )]
+    pub(crate) start_offset_ms: u32,
+    /// Zero to play `loop_count` times instead.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_integral"
+    )]
+    pub(crate) duration_ms: u32,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_integral"
+    )]
+    pub(crate) loop_count: u32,
+}
+
+/// Accepts integral floats such as 500.0, which LLMs sometimes write for
+/// integers.
+fn deserialize_integral<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = f64::deserialize(deserializer)?;
+    if value.fract() != 0.0 || !(0.0..=u32::MAX as f64).contains(&value) {
+        return Err(serde::de::Error::custom(format!(
+            "{} is not a non-negative integer",
+            value
+        )));
+    }
+
+    Ok(value as u32)
+}
+
+impl SequenceSettings {
+    pub(crate) fn schema(
+        &self,
+        channels: &[ActionChannel],
+    ) -> Value {
+        json!({
+            "type": "array",
+            "description": "Optional actions played in order of start_offset_ms after the main reaction, e.g. jump, then run, then cry happily. Set duration_ms for a fixed length or loop_count to repeat.",
+            "maxItems": self.max_length,
+            "items": {
+                "type": "object",
+                "properties": {
+                    "channel": {
+                        "type": "string",
+                        "enum": channels
+                            .iter()
+                            .map(|channel| channel.name.clone())
+                            .collect::<Vec<_>>(),
+                    },
+                    "value": {
+                        "type": "string",
+                        "enum": channels
+                            .iter()
+                            .flat_map(|channel| channel.values.iter())
+                            .map(|value| value.name.clone())
+                            .collect::<Vec<_>>(),
+                    },
+                    "start_offset_ms": {
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "duration_ms": {
+                        "type": "integer",
+                        "minimum": 0,
+                    },
+                    "loop_count": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "maximum": self.max_loop_count,
+                    },
+                },
+                "required": [
+                    "channel",
+                    "value",
+                    "start_offset_ms",
+                ],
+            },
+        })
+    }
+
+    /// Validates the sequence proposed by the LLM. Invalid actions are
+    /// remapped or dropped rather than failing the whole turn.
+    pub(crate) fn parse(
+        &self,
+        value: Option<&Value>,
+        channels: &[ActionChannel],
+    ) -> Vec<TimedAction> {
+        let Some(value) = value else {
+            return Vec::new();
+        };
+
+        let mut actions =
+            match serde_json::from_value::<Vec<TimedAction>>(value.clone()) {
+                | Ok(actions) => actions,
+                | Err(error) => {
+                    tracing::warn!("Dropped invalid sequence: {:?}", error);
+                    return Vec::new();
+                },
+            };
+
+        if actions.len() > self.max_length {
+            tracing::warn!(
+                "Truncated sequence of {} actions to {}",
+                actions.len(),
+                self.max_length
+            );
+            actions.truncate(self.max_length);
+        }
+
+        let mut validated = Vec::new();
+        for mut action in actions {
+            let Some(channel) = channels
+                .iter()
+                .find(|channel| channel.name == action.channel)
+            else {
+                tracing::warn!(
+                    "Dropped action of unknown channel: {:?}",
+                    action
+                );
+                continue;
+            };
+
+            if !channel
+                .values
+                .iter()
+                .any(|value| value.name == action.value)
+            {
+                let Some(fallback) = &channel.fallback else {
+                    tracing::warn!(
+                        "Dropped action of invalid value: {:?}",
+                        action
+                    );
+                    continue;
+                };
+                tracing::warn!(
+                    "Remapped invalid {} {:?} to {} in sequence",
+                    channel.name,
+                    action.value,
+                    fallback
+                );
+                action.value = fallback.clone();
+            }
+
+            if action.duration_ms == 0 && action.loop_count == 0 {
+                action.loop_count = 1;
+            }
+            if action.loop_count > self.max_loop_count {
+                tracing::warn!(
+                    "Clamped loop count {} to {}: {:?}",
+                    action.loop_count,
+                    self.max_loop_count,
+                    action
+                );
+                action.loop_count = self.max_loop_count;
+            }
+
+            let length = match action.duration_ms {
+                | 0 => action
+                    .loop_count
+                    .saturating_mul(self.loop_duration_ms),
+                | duration => duration,
+            };
+            let end = action
+                .start_offset_ms
+                .saturating_add(length);
+            if end > self.max_total_duration_ms {
+                tracing::warn!(
+                    "Dropped action ending at {} ms beyond {} ms: {:?}",
+                    end,
+                    self.max_total_duration_ms,
+                    action
+                );
+                continue;
+            }
+
+            validated.push(action);
+        }
+
+        validated.sort_by_key(|action| action.start_offset_ms);
+
+        validated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::creature::definition::ActionValue;
+
+    fn settings() -> SequenceSettings {
+        SequenceSettings {
+            max_length: 8,
+            max_total_duration_ms: 3000,
+            max_loop_count: 2,
+            loop_duration_ms: 1000,
+        }
+    }
+
+    fn channels() -> Vec<ActionChannel> {
+        vec![ActionChannel {
+            name: "motion".to_string(),
+            description: None,
+            values: vec![ActionValue {
+                name: "MOTION_JUMP".to_string(),
+                description: None,
+            }],
+            fallback: None,
+        }]
+    }
+
+    #[test]
+    fn accepts_integral_floats() {
+        let actions = settings().parse(
+            Some(&json!([{
+                "channel": "motion",
+                "value": "MOTION_JUMP",
+                "start_offset_ms": 500.0,
+                "duration_ms": 1000,
+            }])),
+            &channels(),
+        );
+
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].start_offset_ms, 500);
+    }
+
+    #[test]
+    fn rejects_fractional_numbers() {
+        let actions = settings().parse(
+            Some(&json!([{
+                "channel": "motion",
+                "value": "MOTION_JUMP",
+                "start_offset_ms": 0.5,
+            }])),
+            &channels(),
+        );
+
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn clamps_loop_count() {
+        let actions = settings().parse(
+            Some(&json!([{
+                "channel": "motion",
+                "value": "MOTION_JUMP",
+                "start_offset_ms": 0,
+                "loop_count": 1000000,
+            }])),
+            &channels(),
+        );
+
+        assert_eq!(actions[0].loop_count, 2);
+    }
+
+    #[test]
+    fn drops_loops_ending_too_late() {
+        let actions = settings().parse(
+            Some(&json!([{
+                "channel": "motion",
+                "value": "MOTION_JUMP",
+                "start_offset_ms": 1500,
+                "loop_count": 2,
+            }])),
+            &channels(),
+        );
+
+        assert!(actions.is_empty());
+    }
+}
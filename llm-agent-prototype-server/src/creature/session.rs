@@ -0,0 +1,512 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep_until, Instant};
+use tonic::{Status, Streaming};
+
+use crate::creature::definition::{IdleSettings, Vocabulary};
+use crate::creature::my_creature::creature_rpc;
+use crate::creature::my_creature::creature_rpc::talking::Input;
+use crate::creature::my_creature::{react, Stimulus};
+use crate::creature::registry::CreatureRegistry;
+use crate::rate_limit::{
+    ConnectionLimiter, OverLimitPolicy, RateLimiter, Verdict,
+};
+use crate::rpc_context::RpcContext;
+
+type Sender = mpsc::Sender<Result<creature_rpc::State, Status>>;
+
+/// State of one Talk stream.
+pub(super) struct Session {
+    registry: Arc<CreatureRegistry>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    connection: ConnectionLimiter,
+    over_limit_policy: OverLimitPolicy,
+    /// Creature addressed by metadata unless a message names another.
+    stream_creature_id: Option<String>,
+    /// Creature that acts on idle timers, the last one talked to.
+    idle_creature_id: Option<String>,
+    /// Action values declared by the client on handshake.
+    vocabulary: Option<Vocabulary>,
+    /// Throttled messages waiting to be coalesced, keyed by author and
+    /// creature.
+    pending: HashMap<(String, String), creature_rpc::Talking>,
+    retry_at: Option<Instant>,
+    /// Idle reactions since the last message.
+    unanswered: u32,
+    /// Author of the last message on this stream.
+    last_author: Option<String>,
+    silence_at: Option<Instant>,
+    schedule_at: Option<Instant>,
+    tx: Sender,
+}
+
+impl Session {
+    pub(super) async fn new(
+        registry: Arc<CreatureRegistry>,
+        rate_limiter: Arc<Mutex<RateLimiter>>,
+        stream_creature_id: Option<String>,
+        tx: Sender,
+    ) -> Self {
+        let (connection, over_limit_policy) = {
+            let rate_limiter = rate_limiter.lock().await;
+            (
+                rate_limiter.new_connection(),
+                rate_limiter.over_limit_policy(),
+            )
+        };
+
+        let mut session = Self {
+            registry,
+            rate_limiter,
+            connection,
+            over_limit_policy,
+            idle_creature_id: stream_creature_id.clone(),
+            stream_creature_id,
+            vocabulary: None,
+            pending: HashMap::new(),
+            retry_at: None,
+            unanswered: 0,
+            last_author: None,
+            silence_at: None,
+            schedule_at: None,
+            tx,
+        };
+        session
+            .reset_idle_timers(true)
+            .await;
+
+        session
+    }
+
+    pub(super) async fn run(
+        mut self,
+        mut stream: Streaming<creature_rpc::Talking>,
+    ) {
+        let mut is_first = true;
+        let mut is_open = true;
+
+        loop {
+            let stimuli = tokio::select! {
+                request = stream.next(), if is_open => match request {
+                    | None if self.pending.is_empty() => break,
+                    | None => {
+                        // Held messages are still answered after the client
+                        // stops sending.
+                        tracing::info!(
+                            "Stream ended with {} held messages",
+                            self.pending.len()
+                        );
+                        is_open = false;
+                        Vec::new()
+                    },
+                    | Some(Ok(request)) => {
+                        let is_handshake = is_first;
+                        is_first = false;
+                        match self.receive(request, is_handshake).await {
+                            | Ok(stimuli) => stimuli,
+                            | Err(e) => {
+                                let _ = self.tx.send(Err(e)).await;
+                                break;
+                            },
+                        }
+                    },
+                    | Some(Err(e)) => {
+                        tracing::error!("Failed to receive request: {:?}", e);
+                        let _ = self.tx.send(Err(e)).await;
+                        break;
+                    },
+                },
+                _ = sleep_until(self.retry_at.unwrap_or_else(Instant::now)),
+                    if self.retry_at.is_some() => {
+                    self.retry_at = None;
+                    self.pending
+                        .drain()
+                        .map(|(_, talking)| Stimulus::Talking(talking))
+                        .collect()
+                },
+                _ = sleep_until(self.silence_at.unwrap_or_else(Instant::now)),
+                    if is_open && self.silence_at.is_some() => {
+                    self.silence_at = None;
+                    self.idle_stimulus().await
+                },
+                _ = sleep_until(self.schedule_at.unwrap_or_else(Instant::now)),
+                    if is_open && self.schedule_at.is_some() => {
+                    self.schedule_at = None;
+                    self.reset_idle_timers(true).await;
+                    self.idle_stimulus().await
+                },
+                else => break,
+            };
+
+            for stimulus in stimuli {
+                match self.process(stimulus).await {
+                    | Ok(None) => {},
+                    | Ok(Some(state)) => {
+                        if self
+                            .tx
+                            .send(Ok(state))
+                            .await
+                            .is_err()
+                        {
+                            tracing::error!("Failed to send response");
+                            return;
+                        }
+                    },
+                    | Err(e) => {
+                        tracing::error!("Failed to react: {:?}", e);
+                        let _ = self.tx.send(Err(e)).await;
+                        return;
+                    },
+                }
+            }
+
+            if !is_open && self.pending.is_empty() {
+                break;
+            }
+        }
+    }
+
+    /// Accepts the handshake of the first message, ignoring later ones,
+    /// and turns the input of any message into a stimulus.
+    async fn receive(
+        &mut self,
+        mut request: creature_rpc::Talking,
+        is_first: bool,
+    ) -> Result<Vec<Stimulus>, Status> {
+        if let Some(handshake) = request.handshake.take() {
+            if is_first {
+                let context = self
+                    .find_creature(&request.creature_id)
+                    .ok_or_else(|| {
+                        self.creature_not_found(&request.creature_id)
+                    })?;
+                self.vocabulary =
+                    Some(accept_handshake(&context, handshake).await?);
+            } else {
+                tracing::warn!("Ignored handshake after the first message");
+            }
+
+            // A handshake needs no input.
+            if request.input.is_none() {
+                return Ok(Vec::new());
+            }
+        }
+
+        if request.input.is_none() {
+            tracing::warn!("Ignored message without input");
+            return Ok(Vec::new());
+        }
+        let held = self
+            .pending
+            .remove(&pending_key(&request));
+
+        Ok(vec![Stimulus::Talking(
+            coalesce(held, request),
+        )])
+    }
+
+    /// Reacts to a stimulus unless the rate limiter holds it back.
+    async fn process(
+        &mut self,
+        stimulus: Stimulus,
+    ) -> Result<Option<creature_rpc::State>, Status> {
+        let (creature_id, author) = match &stimulus {
+            | Stimulus::Talking(talking) => {
+                let verdict = self
+                    .rate_limiter
+                    .lock()
+                    .await
+                    .check_message(&talking.author, &mut self.connection);
+                if let Verdict::Throttled {
+                    scope,
+                    bucket,
+                    retry_after,
+                } = verdict
+                {
+                    return Ok(self.hold(stimulus, scope, bucket, retry_after));
+                }
+
+                (
+                    creature_id(
+                        &talking.creature_id,
+                        &self.stream_creature_id,
+                    )
+                    .map(|id| id.to_string()),
+                    Some(talking.author.clone()),
+                )
+            },
+            | Stimulus::Idle {
+                ..
+            } => {
+                if !self
+                    .connection
+                    .has_llm_budget()
+                {
+                    tracing::info!("Skipped idle reaction out of LLM budget");
+                    self.reset_idle_timers(false)
+                        .await;
+                    return Ok(None);
+                }
+
+                (self.idle_creature_id.clone(), None)
+            },
+        };
+
+        let message_creature_id = creature_id
+            .as_deref()
+            .unwrap_or_default();
+        let context = self
+            .find_creature(message_creature_id)
+            .ok_or_else(|| self.creature_not_found(message_creature_id))?;
+        let (state, llm_tokens) = react(
+            context.lock().await,
+            stimulus,
+            self.vocabulary.as_ref(),
+        )
+        .await?;
+
+        self.unanswered = match author {
+            | Some(_) => 0,
+            | None => self.unanswered + 1,
+        };
+        if author.is_some() {
+            self.last_author = author.clone();
+        }
+        match author {
+            | Some(author) => self
+                .rate_limiter
+                .lock()
+                .await
+                .record_llm_tokens(
+                    &author,
+                    &mut self.connection,
+                    llm_tokens,
+                ),
+            | None => self
+                .connection
+                .record_llm_tokens(llm_tokens),
+        }
+
+        // The schedule restarts as well when idle actions had stopped.
+        let is_switched =
+            self.idle_creature_id != creature_id || self.schedule_at.is_none();
+        self.idle_creature_id = creature_id;
+        self.reset_idle_timers(is_switched)
+            .await;
+
+        Ok(Some(state))
+    }
+
+    /// Rejects a throttled message with a notice to the client or holds it
+    /// until the limit allows it.
+    fn hold(
+        &mut self,
+        stimulus: Stimulus,
+        scope: &str,
+        bucket: &str,
+        retry_after: Duration,
+    ) -> Option<creature_rpc::State> {
+        let Stimulus::Talking(talking) = stimulus else {
+            return None;
+        };
+
+        match self.over_limit_policy {
+            | OverLimitPolicy::Reject => Some(creature_rpc::State {
+                throttle: Some(creature_rpc::Throttle {
+                    scope: scope.to_string(),
+                    bucket: bucket.to_string(),
+                    retry_after_ms: retry_after
+                        .as_millis()
+                        .try_into()
+                        .unwrap_or(u32::MAX),
+                }),
+                ..Default::default()
+            }),
+            | OverLimitPolicy::Coalesce => {
+                tracing::info!(
+                    "Holding throttled message from {} for {:?}",
+                    talking.author,
+                    retry_after
+                );
+                let retry = Instant::now() + retry_after;
+                self.retry_at = Some(
+                    self.retry_at
+                        .map_or(retry, |at| at.min(retry)),
+                );
+                self.pending
+                    .insert(pending_key(&talking), talking);
+                None
+            },
+        }
+    }
+
+    fn find_creature(
+        &self,
+        message_creature_id: &str,
+    ) -> Option<Arc<Mutex<RpcContext>>> {
+        self.registry.get(creature_id(
+            message_creature_id,
+            &self.stream_creature_id,
+        ))
+    }
+
+    fn creature_not_found(
+        &self,
+        message_creature_id: &str,
+    ) -> Status {
+        let id = creature_id(
+            message_creature_id,
+            &self.stream_creature_id,
+        );
+        tracing::error!("Creature not found: {:?}", id);
+
+        Status::new(
+            tonic::Code::NotFound,
+            format!(
+                "Creature not found: {}",
+                id.unwrap_or("default")
+            ),
+        )
+    }
+
+    async fn idle_settings(&self) -> Option<IdleSettings> {
+        let context = self.find_creature(
+            self.idle_creature_id
+                .as_deref()
+                .unwrap_or_default(),
+        )?;
+        let context = context.lock().await;
+
+        context
+            .definition
+            .idle
+            .clone()
+    }
+
+    async fn idle_stimulus(&self) -> Vec<Stimulus> {
+        match self.idle_settings().await {
+            | None => Vec::new(),
+            | Some(idle) => {
+                tracing::info!("Creature is idle");
+                vec![Stimulus::Idle {
+                    cue: idle.cue,
+                    last_author: self.last_author.clone(),
+                }]
+            },
+        }
+    }
+
+    /// Restarts the silence timer, and the schedule timer as well when
+    /// requested, or stops both after too many idle reactions in a row.
+    async fn reset_idle_timers(
+        &mut self,
+        reset_schedule: bool,
+    ) {
+        let idle = self.idle_settings().await;
+        let now = Instant::now();
+
+        if idle
+            .as_ref()
+            .is_some_and(|idle| self.unanswered >= idle.max_unanswered)
+        {
+            tracing::info!(
+                "Stopped idle actions after {} without an answer",
+                self.unanswered
+            );
+            self.silence_at = None;
+            self.schedule_at = None;
+            return;
+        }
+
+        self.silence_at = idle
+            .as_ref()
+            .and_then(|idle| idle.after_silence_seconds)
+            .map(|seconds| now + Duration::from_secs(seconds));
+
+        if reset_schedule {
+            self.schedule_at = idle
+                .as_ref()
+                .and_then(|idle| idle.every_seconds)
+                .map(|seconds| now + Duration::from_secs(seconds));
+        }
+    }
+}
+
+/// Resolves the creature of a message, preferring the id in the message
+/// over the one in the stream metadata.
+fn creature_id<'a>(
+    message_creature_id: &'a str,
+    stream_creature_id: &'a Option<String>,
+) -> Option<&'a str> {
+    if message_creature_id.is_empty() {
+        stream_creature_id.as_deref()
+    } else {
+        Some(message_creature_id)
+    }
+}
+
+fn pending_key(talking: &creature_rpc::Talking) -> (String, String) {
+    (
+        talking.author.clone(),
+        talking.creature_id.clone(),
+    )
+}
+
+/// Merges a held back message into the next one from the same author.
+/// Events cannot be merged, so the latest input wins.
+fn coalesce(
+    held: Option<creature_rpc::Talking>,
+    talking: creature_rpc::Talking,
+) -> creature_rpc::Talking {
+    match (held, &talking.input) {
+        | (None, _) => talking,
+        | (Some(mut held), Some(Input::Message(message))) => {
+            if let Some(Input::Message(held_message)) = &mut held.input {
+                held_message.push('\n');
+                held_message.push_str(message);
+                return held;
+            }
+            tracing::info!("Replaced held input by a message");
+            talking
+        },
+        | (Some(_), _) => {
+            tracing::info!("Replaced held input by an event");
+            talking
+        },
+    }
+}
+
+/// Checks the declared action values against the creature definition.
+async fn accept_handshake(
+    context: &Mutex<RpcContext>,
+    handshake: creature_rpc::Handshake,
+) -> Result<Vocabulary, Status> {
+    tracing::info!("Request handshake: {:?}", handshake);
+
+    let mut vocabulary = Vocabulary::new();
+    for channel in handshake.channels {
+        vocabulary
+            .entry(channel.channel)
+            .or_default()
+            .extend(channel.values);
+    }
+
+    context
+        .lock()
+        .await
+        .definition
+        .restrict(&vocabulary)
+        .map_err(|error| {
+            tracing::error!("Invalid handshake: {:?}", error);
+            Status::new(
+                tonic::Code::InvalidArgument,
+                format!("Invalid handshake: {}", error),
+            )
+        })?;
+
+    Ok(vocabulary)
+}
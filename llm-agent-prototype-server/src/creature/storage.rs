@@ -0,0 +1,58 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Reads state persisted as JSON, or the default when nothing is saved yet.
+pub(crate) fn load_json<T>(path: &Path) -> Result<T>
+where
+    T: DeserializeOwned + Default,
+{
+    if !path.exists() {
+        return Ok(T::default());
+    }
+
+    let text = std::fs::read_to_string(path).map_err(|error| {
+        tracing::error!(
+            "Failed to read {}: {:?}",
+            path.display(),
+            error
+        );
+        error
+    })?;
+
+    Ok(serde_json::from_str(&text)?)
+}
+
+pub(crate) async fn save_json<T>(
+    path: &Path,
+    value: &T,
+) -> Result<()>
+where
+    T: Serialize,
+{
+    if let Some(directory) = path.parent() {
+        tokio::fs::create_dir_all(directory).await?;
+    }
+
+    // Write to a sibling file first so a crash cannot truncate the file.
+    let temporary = path.with_extension("json.tmp");
+    tokio::fs::write(
+        &temporary,
+        serde_json::to_string_pretty(value)?,
+    )
+    .await?;
+    tokio::fs::rename(&temporary, path)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to save {}: {:?}",
+                path.display(),
+                error
+            );
+            error
+        })?;
+
+    Ok(())
+}
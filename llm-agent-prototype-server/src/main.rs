@@ -1,26 +1,37 @@
 mod certification;
 mod chat_gpt_api;
+mod config;
 mod creature;
 mod error_mapping;
 mod logging;
+mod rate_limit;
 mod rpc_context;
 mod vector_db;
 
-use crate::chat_gpt_api::memory::FiniteQueueMemory;
-use crate::chat_gpt_api::specification::Model;
+use crate::config::{Cli, Command, Config};
 use crate::creature::my_creature::creature_rpc::creature_server::CreatureServer;
 use crate::creature::my_creature::MyCreature;
-use crate::rpc_context::RpcContext;
+use crate::creature::registry::CreatureRegistry;
+use crate::rate_limit::RateLimiter;
 use crate::vector_db::embeddings;
+use crate::vector_db::store::{Connection, StoreBackend};
+use clap::Parser;
 use qdrant_client::prelude::QdrantClient;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use tonic::transport::Server;
-use vector_db::database::DataBase;
 
 #[tracing::instrument(name = "main", err)]
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+    if cli.print_config {
+        print!("{}", config.to_toml()?);
+        return Ok(());
+    }
+
     crate::logging::initialize_logging().map_err(|error| {
         tracing::error!(
             "Failed to initialize logging: {:?}",
@@ -28,10 +39,29 @@ async fn main() -> anyhow::Result<()> {
         );
         error
     })?;
+    for warning in &config.warnings {
+        tracing::warn!("{}", warning);
+    }
+
+    if let Some(Command::DownloadModel {
+        ..
+    }) = cli.command
+    {
+        let path = embeddings::download(&config.embedding)
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to download model: {:?}", error);
+                error
+            })?;
+        tracing::info!("Downloaded model to {}", path.display());
+        return Ok(());
+    }
 
-    tracing::info!("Starting server...");
+    tracing::info!("Starting server with {:?}", config);
 
-    let address = "0.0.0.0:50051"
+    let address: SocketAddr = config
+        .server
+        .address
         .parse()
         .map_err(|error| {
             tracing::error!("Failed to parse address: {:?}", error);
@@ -39,57 +69,62 @@ async fn main() -> anyhow::Result<()> {
         })?;
 
     // create our state
-    let model = Model::Gpt35Turbo0613;
-    let prompt = "Your are an AI assistant.".to_string();
-    let context_memory = FiniteQueueMemory::new(10);
-    let qdrant_client = QdrantClient::from_url("http://qdrant:6334")
-        .build()
-        .map_err(|error| {
-            tracing::error!(
-                "Failed to create qdrant client: {:?}",
-                error
-            );
-            error
-        })?;
-    qdrant_client
-        .health_check()
+    let connection = match config.vector_store.backend {
+        | StoreBackend::Qdrant => {
+            let qdrant_client = QdrantClient::from_url(&config.qdrant.url)
+                .build()
+                .map_err(|error| {
+                    tracing::error!(
+                        "Failed to create qdrant client: {:?}",
+                        error
+                    );
+                    error
+                })?;
+            qdrant_client
+                .health_check()
+                .await
+                .map_err(|error| {
+                    tracing::error!(
+                        "Failed to check qdrant health: {:?}",
+                        error
+                    );
+                    error
+                })?;
+            Connection::Qdrant(Arc::new(qdrant_client))
+        },
+        | StoreBackend::Memory => Connection::Memory,
+    };
+    let embedder = embeddings::start(&config.embedding)
         .await
         .map_err(|error| {
             tracing::error!(
-                "Failed to check qdrant health: {:?}",
+                "Failed to start embedding service: {:?}",
                 error
             );
             error
         })?;
-    let dimension = embeddings::get_dimension()
-        .await
-        .map_err(|error| {
-            tracing::error!("Failed to get dimension: {:?}", error);
-            error
-        })?;
-    let long_memory = DataBase::new(
-        qdrant_client,
-        "long_memory".to_string(),
-        dimension,
-        true, // Reset to develop
+    let registry = CreatureRegistry::load(
+        config.creatures.clone(),
+        connection,
+        config.vector_store.clone(),
+        embedder,
     )
     .await
     .map_err(|error| {
-        tracing::error!(
-            "Failed to create long memory: {:?}",
-            error
-        );
+        tracing::error!("Failed to load creatures: {:?}", error);
         error
     })?;
-    let rpc_context = Arc::new(Mutex::new(RpcContext {
-        model,
-        prompt,
-        context_memory,
-        long_memory,
-    }));
+
+    let rate_limiter = Arc::new(Mutex::new(RateLimiter::new(
+        config.rate_limit.clone(),
+    )));
+
+    let registry = Arc::new(registry);
+    tokio::spawn(registry.clone().watch());
 
     let creature = MyCreature {
-        context: rpc_context,
+        registry: registry.clone(),
+        rate_limiter,
     };
 
     let reflection_server = tonic_reflection::server::Builder::configure()
@@ -123,12 +158,54 @@ async fn main() -> anyhow::Result<()> {
         })?
         .add_service(CreatureServer::new(creature))
         .add_service(reflection_server)
-        .serve(address)
+        .serve_with_shutdown(address, shutdown_signal())
         .await
         .map_err(|error| {
             tracing::error!("Failed to serve: {:?}", error);
             error
         })?;
 
+    registry.close().await;
+
     Ok(())
 }
+
+/// Waits for Ctrl+C or, on Unix, SIGTERM from e.g. `docker stop`.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            tracing::error!(
+                "Failed to listen for Ctrl+C: {:?}",
+                error
+            );
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(
+            tokio::signal::unix::SignalKind::terminate(),
+        ) {
+            | Ok(mut signal) => {
+                signal.recv().await;
+            },
+            | Err(error) => {
+                tracing::error!(
+                    "Failed to listen for SIGTERM: {:?}",
+                    error
+                );
+                std::future::pending::<()>().await;
+            },
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("Shutting down server");
+}
@@ -0,0 +1,493 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Capacity and refill speed of one token bucket.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Limit {
+    pub(crate) capacity: f64,
+    pub(crate) refill_per_second: f64,
+}
+
+/// What to do with a message that exceeds a limit.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OverLimitPolicy {
+    /// Drop the message with a notice and keep the stream open.
+    Reject,
+    /// Hold the message and merge it into the next one that passes.
+    Coalesce,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    pub(crate) per_author_messages: Limit,
+    pub(crate) per_author_llm_tokens: Limit,
+    pub(crate) per_connection_messages: Limit,
+    pub(crate) per_connection_llm_tokens: Limit,
+    pub(crate) over_limit: OverLimitPolicy,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            per_author_messages: Limit {
+                capacity: 5.0,
+                refill_per_second: 0.2,
+            },
+            per_author_llm_tokens: Limit {
+                capacity: 10000.0,
+                refill_per_second: 20.0,
+            },
+            per_connection_messages: Limit {
+                capacity: 10.0,
+                refill_per_second: 0.5,
+            },
+            per_connection_llm_tokens: Limit {
+                capacity: 20000.0,
+                refill_per_second: 40.0,
+            },
+            over_limit: OverLimitPolicy::Coalesce,
+        }
+    }
+}
+
+/// Retry delay reported by a bucket that never refills.
+const NEVER_REFILLS: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug)]
+struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now
+            .duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.refill_per_second)
+            .min(self.limit.capacity);
+        self.last_refill = now;
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.limit.capacity
+    }
+
+    /// Time until the bucket holds at least `amount` tokens.
+    fn wait_for(
+        &mut self,
+        amount: f64,
+    ) -> Option<Duration> {
+        self.refill();
+        if self.tokens >= amount {
+            return None;
+        }
+        if self.limit.refill_per_second <= 0.0 {
+            return Some(NEVER_REFILLS);
+        }
+
+        Some(Duration::from_secs_f64(
+            (amount - self.tokens) / self.limit.refill_per_second,
+        ))
+    }
+
+    /// Takes tokens even if the bucket goes into debt.
+    fn consume(
+        &mut self,
+        amount: f64,
+    ) {
+        self.refill();
+        self.tokens -= amount;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    messages: TokenBucket,
+    llm_tokens: TokenBucket,
+}
+
+impl Buckets {
+    fn new(
+        messages: Limit,
+        llm_tokens: Limit,
+    ) -> Self {
+        Self {
+            messages: TokenBucket::new(messages),
+            llm_tokens: TokenBucket::new(llm_tokens),
+        }
+    }
+
+    /// Messages need a whole token, LLM tokens only need the bucket to be
+    /// out of debt because the cost of a turn is known after the call.
+    fn wait(&mut self) -> Option<(Duration, &'static str)> {
+        let messages = self
+            .messages
+            .wait_for(1.0)
+            .map(|wait| (wait, "messages"));
+        let llm_tokens = self
+            .llm_tokens
+            .wait_for(f64::MIN_POSITIVE)
+            .map(|wait| (wait, "llm_tokens"));
+
+        match (messages, llm_tokens) {
+            | (Some(messages), Some(llm_tokens)) => Some(std::cmp::max_by_key(
+                messages,
+                llm_tokens,
+                |(wait, _)| *wait,
+            )),
+            | (messages, llm_tokens) => messages.or(llm_tokens),
+        }
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.messages.is_full() && self.llm_tokens.is_full()
+    }
+}
+
+/// Result of asking the limiter whether a message may be processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allowed,
+    Throttled {
+        scope: &'static str,
+        bucket: &'static str,
+        retry_after: Duration,
+    },
+}
+
+/// Token buckets shared by every connection, keyed by author.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    authors: HashMap<String, Buckets>,
+}
+
+/// Token buckets of one Talk stream.
+#[derive(Debug)]
+pub(crate) struct ConnectionLimiter {
+    buckets: Buckets,
+}
+
+impl ConnectionLimiter {
+    /// Whether the connection may spend LLM tokens on turns nobody asked
+    /// for, such as idle behavior.
+    pub(crate) fn has_llm_budget(&mut self) -> bool {
+        self.buckets
+            .llm_tokens
+            .wait_for(f64::MIN_POSITIVE)
+            .is_none()
+    }
+
+    pub(crate) fn record_llm_tokens(
+        &mut self,
+        tokens: u64,
+    ) {
+        self.buckets
+            .llm_tokens
+            .consume(tokens as f64);
+    }
+}
+
+/// Number of tracked authors above which idle buckets are dropped.
+const PRUNE_THRESHOLD: usize = 1024;
+
+impl RateLimiter {
+    pub(crate) fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            authors: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn over_limit_policy(&self) -> OverLimitPolicy {
+        self.config.over_limit
+    }
+
+    pub(crate) fn new_connection(&self) -> ConnectionLimiter {
+        ConnectionLimiter {
+            buckets: Buckets::new(
+                self.config
+                    .per_connection_messages,
+                self.config
+                    .per_connection_llm_tokens,
+            ),
+        }
+    }
+
+    /// Takes one message token from both the author and the connection
+    /// when both allow it, otherwise takes nothing.
+    pub(crate) fn check_message(
+        &mut self,
+        author: &str,
+        connection: &mut ConnectionLimiter,
+    ) -> Verdict {
+        self.prune();
+
+        let author_buckets = self.author_buckets(author);
+
+        let throttled = match (
+            author_buckets.wait(),
+            connection.buckets.wait(),
+        ) {
+            | (Some((wait, bucket)), _) => Some(("author", bucket, wait)),
+            | (None, Some((wait, bucket))) => {
+                Some(("connection", bucket, wait))
+            },
+            | (None, None) => None,
+        };
+
+        if let Some((scope, bucket, retry_after)) = throttled {
+            tracing::warn!(
+                author,
+                scope,
+                bucket,
+                retry_after_ms = retry_after.as_millis() as u64,
+                "Throttled message"
+            );
+            return Verdict::Throttled {
+                scope,
+                bucket,
+                retry_after,
+            };
+        }
+
+        author_buckets
+            .messages
+            .consume(1.0);
+        connection
+            .buckets
+            .messages
+            .consume(1.0);
+
+        Verdict::Allowed
+    }
+
+    /// Charges the LLM tokens spent by a turn after the fact.
+    pub(crate) fn record_llm_tokens(
+        &mut self,
+        author: &str,
+        connection: &mut ConnectionLimiter,
+        tokens: u64,
+    ) {
+        tracing::debug!(
+            author,
+            tokens,
+            "Recording LLM token usage"
+        );
+
+        self.author_buckets(author)
+            .llm_tokens
+            .consume(tokens as f64);
+        connection.record_llm_tokens(tokens);
+    }
+
+    fn author_buckets(
+        &mut self,
+        author: &str,
+    ) -> &mut Buckets {
+        let config = &self.config;
+        self.authors
+            .entry(author.to_string())
+            .or_insert_with(|| {
+                Buckets::new(
+                    config.per_author_messages,
+                    config.per_author_llm_tokens,
+                )
+            })
+    }
+
+    fn prune(&mut self) {
+        if self.authors.len() < PRUNE_THRESHOLD {
+            return;
+        }
+
+        // Full buckets carry no state worth keeping.
+        self.authors
+            .retain(|_, buckets| !buckets.is_full());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: Limit = Limit {
+        capacity: 2.0,
+        refill_per_second: 1.0,
+    };
+
+    fn config() -> RateLimitConfig {
+        RateLimitConfig {
+            per_author_messages: LIMIT,
+            per_author_llm_tokens: Limit {
+                capacity: 100.0,
+                refill_per_second: 1.0,
+            },
+            per_connection_messages: Limit {
+                capacity: 3.0,
+                refill_per_second: 1.0,
+            },
+            per_connection_llm_tokens: Limit {
+                capacity: 100.0,
+                refill_per_second: 1.0,
+            },
+            over_limit: OverLimitPolicy::Reject,
+        }
+    }
+
+    #[test]
+    fn bucket_starts_full() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.wait_for(2.0), None);
+    }
+
+    #[test]
+    fn bucket_waits_for_missing_tokens() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.consume(2.0);
+
+        let wait = bucket.wait_for(1.0).unwrap();
+        assert!(wait <= Duration::from_secs(1));
+        assert!(wait > Duration::from_millis(900));
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.consume(2.0);
+        bucket.last_refill -= Duration::from_secs(10);
+
+        bucket.refill();
+        assert_eq!(bucket.tokens, LIMIT.capacity);
+    }
+
+    #[test]
+    fn bucket_goes_into_debt() {
+        let mut bucket = TokenBucket::new(LIMIT);
+        bucket.consume(4.0);
+
+        assert!(bucket.tokens < 0.0);
+        assert!(bucket
+            .wait_for(f64::MIN_POSITIVE)
+            .is_some());
+    }
+
+    #[test]
+    fn bucket_without_refill_never_refills() {
+        let mut bucket = TokenBucket::new(Limit {
+            capacity: 1.0,
+            refill_per_second: 0.0,
+        });
+        bucket.consume(1.0);
+
+        assert_eq!(
+            bucket.wait_for(1.0),
+            Some(NEVER_REFILLS)
+        );
+    }
+
+    #[test]
+    fn author_limit_throttles_across_connections() {
+        let mut limiter = RateLimiter::new(config());
+        let mut first = limiter.new_connection();
+        let mut second = limiter.new_connection();
+
+        assert_eq!(
+            limiter.check_message("alice", &mut first),
+            Verdict::Allowed
+        );
+        assert_eq!(
+            limiter.check_message("alice", &mut second),
+            Verdict::Allowed
+        );
+        assert!(matches!(
+            limiter.check_message("alice", &mut first),
+            Verdict::Throttled {
+                scope: "author",
+                bucket: "messages",
+                ..
+            }
+        ));
+        assert_eq!(
+            limiter.check_message("bob", &mut first),
+            Verdict::Allowed
+        );
+    }
+
+    #[test]
+    fn connection_limit_throttles_across_authors() {
+        let mut limiter = RateLimiter::new(config());
+        let mut connection = limiter.new_connection();
+
+        for author in [
+            "alice", "bob", "carol",
+        ] {
+            assert_eq!(
+                limiter.check_message(author, &mut connection),
+                Verdict::Allowed
+            );
+        }
+        assert!(matches!(
+            limiter.check_message("dave", &mut connection),
+            Verdict::Throttled {
+                scope: "connection",
+                bucket: "messages",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn throttled_message_takes_no_tokens() {
+        let mut limiter = RateLimiter::new(config());
+        let mut connection = limiter.new_connection();
+        limiter
+            .author_buckets("alice")
+            .messages
+            .consume(2.0);
+
+        assert!(matches!(
+            limiter.check_message("alice", &mut connection),
+            Verdict::Throttled { .. }
+        ));
+        assert!(connection
+            .buckets
+            .messages
+            .is_full());
+    }
+
+    #[test]
+    fn llm_token_debt_throttles_the_author() {
+        let mut limiter = RateLimiter::new(config());
+        let mut connection = limiter.new_connection();
+        limiter.record_llm_tokens("alice", &mut connection, 150);
+
+        assert!(matches!(
+            limiter.check_message("alice", &mut connection),
+            Verdict::Throttled {
+                scope: "author",
+                bucket: "llm_tokens",
+                ..
+            }
+        ));
+        assert!(!connection.has_llm_budget());
+    }
+}
@@ -1,11 +1,21 @@
 use crate::chat_gpt_api::memory::FiniteQueueMemory;
-use crate::chat_gpt_api::specification::Model;
+use crate::creature::affect::Affect;
+use crate::creature::definition::CreatureDefinition;
+use crate::creature::needs::Needs;
+use crate::creature::policy::Policies;
+use crate::creature::relationship::Relationships;
 use crate::vector_db::database::DataBase;
 
 #[derive(Debug)]
 pub(crate) struct RpcContext {
-    pub(crate) model: Model,
-    pub(crate) prompt: String,
+    pub(crate) definition: CreatureDefinition,
     pub(crate) context_memory: FiniteQueueMemory,
     pub(crate) long_memory: DataBase,
+    /// Emotion intensities when the definition has affect settings.
+    pub(crate) affect: Option<Affect>,
+    /// Values per author when the definition has relationship settings.
+    pub(crate) relationships: Option<Relationships>,
+    pub(crate) policies: Policies,
+    /// Drives when the definition has needs settings.
+    pub(crate) needs: Option<Needs>,
 }
@@ -1,2 +1,3 @@
 pub(super) mod database;
 pub(super) mod embeddings;
+pub(super) mod store;
@@ -1,167 +1,259 @@
-use std::{collections::HashMap, fmt::Formatter};
-
-use anyhow::Result;
-use chrono::{DateTime, Utc};
-use qdrant_client::{
-    prelude::{Payload, QdrantClient},
-    qdrant::{
-        vectors_config::Config, CreateCollection, Distance, Filter,
-        PointStruct, ScoredPoint, SearchPoints, Value, VectorParams,
-        VectorsConfig,
-    },
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_json::Value;
+
+use crate::vector_db::embeddings::Embedder;
+use crate::vector_db::store::{
+    Condition, FieldKind, Filter, Payload, ScoredPoint, StoredPoint,
+    VectorStore,
 };
 
-use crate::vector_db::embeddings;
+/// Format of `datetime` in payloads.
+const DATETIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.3f";
+
+pub(crate) const AUTHOR_KEY: &str = "author";
+
+/// Payload key of `datetime` in Unix seconds, for filtering by time.
+pub(crate) const TIMESTAMP_KEY: &str = "timestamp";
+
+pub(crate) const VISIBILITY_KEY: &str = "visibility";
+
+/// Payload key separating creatures that share a collection.
+const NAMESPACE_KEY: &str = "namespace";
+
+/// Importance of records written before importance existed.
+const DEFAULT_IMPORTANCE: f64 = 0.5;
+
+/// Payload fields indexed when a collection is created. Every retrieval
+/// filters by namespace and most by visibility.
+pub(crate) const INDEXED_FIELDS: [(&str, FieldKind); 4] = [
+    (NAMESPACE_KEY, FieldKind::Keyword),
+    (AUTHOR_KEY, FieldKind::Keyword),
+    (VISIBILITY_KEY, FieldKind::Keyword),
+    (TIMESTAMP_KEY, FieldKind::Integer),
+];
+
+/// What a record remembers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RecordKind {
+    /// Something someone said.
+    Speech,
+    /// Something that happened in the world.
+    Event,
+    /// How the creature itself felt and acted, authored by whom it reacted
+    /// to.
+    Reaction,
+}
+
+impl RecordKind {
+    pub(crate) fn parse_to_string(&self) -> String {
+        match self {
+            | RecordKind::Speech => "speech".to_string(),
+            | RecordKind::Event => "event".to_string(),
+            | RecordKind::Reaction => "reaction".to_string(),
+        }
+    }
+
+    pub(crate) fn parse_to_kind(input: &str) -> Result<RecordKind> {
+        match input {
+            | "speech" => Ok(RecordKind::Speech),
+            | "event" => Ok(RecordKind::Event),
+            | "reaction" => Ok(RecordKind::Reaction),
+            | _ => Err(anyhow!(
+                "Invalid record kind: {}",
+                input
+            )),
+        }
+    }
+}
+
+/// Who may recall a record besides its author.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Visibility {
+    /// Only its author.
+    Private,
+    /// Anyone.
+    Public,
+}
+
+impl Visibility {
+    pub(crate) fn parse_to_string(&self) -> String {
+        match self {
+            | Visibility::Private => "private".to_string(),
+            | Visibility::Public => "public".to_string(),
+        }
+    }
+
+    pub(crate) fn parse_to_visibility(input: &str) -> Result<Visibility> {
+        match input {
+            | "private" => Ok(Visibility::Private),
+            | "public" => Ok(Visibility::Public),
+            | _ => Err(anyhow!("Invalid visibility: {}", input)),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct Record {
     pub(crate) text: String,
     pub(crate) datetime: DateTime<Utc>,
     pub(crate) author: String,
+    pub(crate) kind: RecordKind,
+    pub(crate) visibility: Visibility,
+    /// How much the record matters in [0, 1], rated when it is written.
+    pub(crate) importance: f64,
 }
 
 impl Record {
     pub(crate) fn new(
         text: String,
         author: String,
+        kind: RecordKind,
+        visibility: Visibility,
+        importance: f64,
     ) -> Self {
         Self {
             text,
             datetime: Utc::now(),
             author,
+            kind,
+            visibility,
+            importance,
         }
     }
 
-    fn to_payload(&self) -> Payload {
-        let mut map = HashMap::new();
+    pub(crate) fn to_payload(&self) -> Payload {
+        let mut payload = Payload::new();
 
-        map.insert(
+        payload.insert(
             "text".to_string(),
             Value::from(self.text.clone()),
         );
 
-        map.insert(
+        payload.insert(
             "datetime".to_string(),
             Value::from(
                 self.datetime
-                    .format("%Y-%m-%dT%H:%M:%S%.3f")
+                    .format(DATETIME_FORMAT)
                     .to_string(),
             ),
         );
 
-        map.insert(
-            "author".to_string(),
+        payload.insert(
+            TIMESTAMP_KEY.to_string(),
+            Value::from(self.datetime.timestamp()),
+        );
+
+        payload.insert(
+            AUTHOR_KEY.to_string(),
             Value::from(self.author.clone()),
         );
 
-        Payload::new_from_hashmap(map)
+        payload.insert(
+            "kind".to_string(),
+            Value::from(self.kind.parse_to_string()),
+        );
+
+        payload.insert(
+            VISIBILITY_KEY.to_string(),
+            Value::from(
+                self.visibility
+                    .parse_to_string(),
+            ),
+        );
+
+        payload.insert(
+            "importance".to_string(),
+            Value::from(self.importance),
+        );
+
+        payload
     }
 
-    pub(crate) fn from_payload(payload: HashMap<String, Value>) -> Self {
-        let text = payload
-            .get("text")
-            .unwrap()
-            .to_string()
-            .parse::<String>()
-            .unwrap();
-
-        let datetime = payload
-            .get("datetime")
-            .unwrap()
-            .to_string()
-            .parse::<DateTime<Utc>>()
-            .unwrap();
-
-        let author = payload
-            .get("author")
-            .unwrap()
-            .to_string()
-            .parse::<String>()
-            .unwrap();
+    pub(crate) fn from_payload(payload: &Payload) -> Result<Self> {
+        let text = string_field(payload, "text")?;
 
-        Self {
+        let datetime = NaiveDateTime::parse_from_str(
+            &string_field(payload, "datetime")?,
+            DATETIME_FORMAT,
+        )?;
+
+        let author = string_field(payload, AUTHOR_KEY)?;
+
+        // Records written before kinds existed are all speech.
+        let kind = match string_field(payload, "kind") {
+            | Ok(kind) => RecordKind::parse_to_kind(&kind)?,
+            | Err(_) => RecordKind::Speech,
+        };
+
+        // Records written before visibility existed stay with their author.
+        let visibility = match string_field(payload, VISIBILITY_KEY) {
+            | Ok(visibility) => Visibility::parse_to_visibility(&visibility)?,
+            | Err(_) => Visibility::Private,
+        };
+
+        let importance = payload
+            .get("importance")
+            .and_then(|importance| importance.as_f64())
+            .unwrap_or(DEFAULT_IMPORTANCE);
+
+        Ok(Self {
             text,
-            datetime,
+            datetime: Utc.from_utc_datetime(&datetime),
             author,
-        }
+            kind,
+            visibility,
+            importance,
+        })
     }
 }
 
-pub(crate) struct DataBase {
-    pub(crate) client: QdrantClient,
-    pub(crate) name: String,
+fn string_field(
+    payload: &Payload,
+    key: &str,
+) -> Result<String> {
+    match payload.get(key) {
+        | Some(Value::String(value)) => Ok(value.clone()),
+        | _ => Err(anyhow!("Missing string field: {}", key)),
+    }
 }
 
-impl std::fmt::Debug for DataBase {
-    fn fmt(
-        &self,
-        f: &mut Formatter<'_>,
-    ) -> std::fmt::Result {
-        f.debug_struct("DataBase")
-            .field("client", &self.client.cfg.uri)
-            .field("name", &self.name)
-            .finish()
-    }
+/// Memories of a creature in a vector store.
+#[derive(Debug)]
+pub(crate) struct DataBase {
+    pub(crate) store: Arc<dyn VectorStore>,
+    pub(crate) namespace: Option<String>,
+    pub(crate) embeddings: Arc<dyn Embedder>,
 }
 
 impl DataBase {
-    pub(crate) async fn new(
-        client: QdrantClient,
-        name: String,
-        dimension: u64,
-        reset: bool,
-    ) -> Result<DataBase> {
-        let has_collection = client
-            .has_collection(name.clone())
-            .await
-            .map_err(|error| {
-                tracing::error!(
-                    "Failed to check collection: {:?}",
-                    error
-                );
-                error
-            })?;
-
-        if reset & has_collection {
-            client
-                .delete_collection(name.clone())
-                .await
-                .map_err(|error| {
-                    tracing::error!(
-                        "Failed to delete collection: {:?}",
-                        error
-                    );
-                    error
-                })?;
+    pub(crate) fn new(
+        store: Arc<dyn VectorStore>,
+        embeddings: Arc<dyn Embedder>,
+    ) -> DataBase {
+        DataBase {
+            store,
+            namespace: None,
+            embeddings,
         }
+    }
 
-        if reset || !has_collection {
-            client
-                .create_collection(&CreateCollection {
-                    collection_name: name.clone(),
-                    vectors_config: Some(VectorsConfig {
-                        config: Some(Config::Params(VectorParams {
-                            size: dimension,
-                            distance: Distance::Cosine.into(),
-                            ..Default::default()
-                        })),
-                    }),
-                    ..Default::default()
-                })
-                .await
-                .map_err(|error| {
-                    tracing::error!(
-                        "Failed to create collection: {:?}",
-                        error
-                    );
-                    error
-                })?;
-        }
+    pub(crate) async fn close(&self) -> Result<()> {
+        self.store.close().await
+    }
 
-        Ok(DataBase {
-            client,
-            name,
-        })
+    /// View of the same collection restricted to one namespace.
+    pub(crate) fn with_namespace(
+        &self,
+        namespace: Option<String>,
+    ) -> DataBase {
+        DataBase {
+            store: self.store.clone(),
+            namespace,
+            embeddings: self.embeddings.clone(),
+        }
     }
 
     #[tracing::instrument(
@@ -173,35 +265,34 @@ impl DataBase {
         &mut self,
         record: Record,
     ) -> Result<()> {
-        let embedding = embeddings::embed(record.text.clone())
+        let vector = self
+            .embeddings
+            .embed(record.text.clone())
             .await
             .map_err(|error| {
                 tracing::error!("Failed to embed text: {:?}", error);
                 error
             })?;
-        let payload = record.to_payload();
-        let mut points = Vec::new();
-        for vector in embedding {
-            let point = PointStruct::new(
-                uuid::Uuid::new_v4().to_string(),
-                vector,
-                payload.clone(),
+        let mut payload = record.to_payload();
+        if let Some(namespace) = &self.namespace {
+            payload.insert(
+                NAMESPACE_KEY.to_string(),
+                Value::from(namespace.clone()),
             );
-            points.push(point);
         }
 
-        self.client
-            .upsert_points(self.name.clone(), points, None)
-            .await
-            .map_err(|error| {
-                tracing::error!("Failed to upsert points: {:?}", error);
-                error
-            })?;
+        self.store
+            .upsert(vec![StoredPoint {
+                id: uuid::Uuid::new_v4().to_string(),
+                vector,
+                payload,
+            }])
+            .await?;
 
         tracing::info!(
             "Upserted {} to {} successfully",
             record.text,
-            self.name
+            self.store.name()
         );
         Ok(())
     }
@@ -217,36 +308,34 @@ impl DataBase {
         count_limit: u64,
         filter: Option<Filter>,
     ) -> Result<Vec<ScoredPoint>> {
-        let embedding = embeddings::embed(query.clone())
+        let vector = self
+            .embeddings
+            .embed(query.clone())
             .await
             .map_err(|error| {
                 tracing::error!("Failed to embed query: {:?}", error);
                 error
             })?;
-        let vector = embedding[0].clone();
+        let mut filter = filter.unwrap_or_default();
+        if let Some(namespace) = &self.namespace {
+            filter
+                .must
+                .push(Condition::equals(
+                    NAMESPACE_KEY,
+                    namespace.clone(),
+                ));
+        }
         let result = self
-            .client
-            .search_points(&SearchPoints {
-                collection_name: self.name.clone(),
-                vector,
-                limit: count_limit,
-                filter,
-                with_payload: Some(true.into()),
-                with_vectors: Some(true.into()),
-                ..Default::default()
-            })
-            .await
-            .map_err(|error| {
-                tracing::error!("Failed to search points: {:?}", error);
-                error
-            })?;
+            .store
+            .search(vector, count_limit, &filter)
+            .await?;
 
         tracing::info!(
             "Searched {}'s results by query: {} from {} successfully",
-            result.result.len(),
+            result.len(),
             query,
-            self.name
+            self.store.name()
         );
-        Ok(result.result)
+        Ok(result)
     }
 }
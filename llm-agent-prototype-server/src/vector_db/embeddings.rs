@@ -1,79 +1,117 @@
-use anyhow::{Error, Result};
-use rust_bert::pipelines::sentence_embeddings::{
-    SentenceEmbeddingsBuilder, SentenceEmbeddingsModelType,
+pub(super) mod cache;
+pub(crate) mod hashing;
+pub(super) mod local;
+pub(super) mod remote;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::vector_db::embeddings::{
+    cache::CachedEmbedder,
+    hashing::HashingEmbedder,
+    local::{LocalEmbedder, LocalModel},
+    remote::RemoteEmbedder,
 };
-use tokio::task;
-
-#[tracing::instrument(name = "vector_db.embeddings.embed", err)]
-pub(crate) async fn embed(sentence: String) -> Result<Vec<Vec<f32>>> {
-    let embeddings_result: Result<Vec<Vec<f32>>, Error> =
-        task::spawn_blocking(move || {
-            // Setup sentence embeddings model
-            let model = SentenceEmbeddingsBuilder::remote(
-                SentenceEmbeddingsModelType::AllMiniLmL6V2,
-            )
-            .create_model()
-            .map_err(|error| {
-                tracing::error!("Failed to create model: {:?}", error);
-                error
-            })?;
-
-            // Generate Embeddings
-            let embeddings = model
-                .encode(&[sentence])
-                .map_err(|error| {
-                    tracing::error!("Failed to encode sentence: {:?}", error);
-                    error
-                })?;
-
-            Ok(embeddings)
-        })
-        .await
-        .map_err(|error| {
-            tracing::error!(
-                "Failed to spawn blocking task: {:?}",
-                error
-            );
-            error
-        })?;
 
-    embeddings_result
+/// Turns sentences into vectors of the vector database.
+#[tonic::async_trait]
+pub(crate) trait Embedder: Send + Sync + std::fmt::Debug {
+    /// Name of the model, since vectors of different models do not mix.
+    fn model_id(&self) -> &str;
+
+    fn dimension(&self) -> u64;
+
+    async fn embed_batch(
+        &self,
+        sentences: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>>;
+
+    async fn embed(
+        &self,
+        sentence: String,
+    ) -> Result<Vec<f32>> {
+        self.embed_batch(vec![sentence])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow!("No embedding returned"))
+    }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EmbeddingBackend {
+    /// rust-bert model run on this machine.
+    Local,
+    /// OpenAI compatible `/v1/embeddings` API.
+    Remote,
+    /// Deterministic hashing of words, needing no model.
+    Hashing,
+}
+
+/// Which embedder the server uses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EmbeddingConfig {
+    pub(crate) backend: EmbeddingBackend,
+    /// Model of the local backend.
+    pub(crate) local_model: LocalModel,
+    /// Directory of local models downloaded ahead by the download-model
+    /// command. The model is downloaded on start when omitted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) models_directory: Option<String>,
+    /// Endpoint of the remote backend.
+    pub(crate) url: String,
+    /// Model of the remote backend.
+    pub(crate) model: String,
+    /// Environment variable holding the API key of the remote backend.
+    pub(crate) api_key_env: String,
+    /// Vector size of the hashing backend.
+    pub(crate) dimension: u64,
+    /// Number of embeddings kept in memory, zero to disable the cache.
+    pub(crate) cache_capacity: usize,
+    /// Directory keeping embeddings across restarts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) cache_directory: Option<String>,
+    /// Number of embeddings kept in the directory, the least recently used
+    /// deleted first.
+    pub(crate) cache_directory_capacity: usize,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            backend: EmbeddingBackend::Local,
+            local_model: LocalModel::AllMiniLmL6V2,
+            models_directory: None,
+            url: "https://api.openai.com/v1/embeddings".to_string(),
+            model: "text-embedding-ada-002".to_string(),
+            api_key_env: "OPENAI_API_KEY".to_string(),
+            dimension: 384,
+            cache_capacity: 1024,
+            cache_directory: None,
+            cache_directory_capacity: 100_000,
+        }
+    }
+}
+
+/// Downloads the local model into the models directory ahead of running
+/// offline.
 #[tracing::instrument(
-    name = "vector_db.embeddings.get_dimension",
+    name = "vector_db.embeddings.download",
     err
 )]
-pub(crate) async fn get_dimension() -> Result<u64> {
-    let get_dimension_result: Result<u64, Error> =
-        task::spawn_blocking(move || {
-            // Setup sentence embeddings model
-            let model = SentenceEmbeddingsBuilder::remote(
-                SentenceEmbeddingsModelType::AllMiniLmL6V2,
-            )
-            .create_model()
-            .map_err(|error| {
-                tracing::error!("Failed to create model: {:?}", error);
-                error
-            })?;
-
-            let dimension = model
-                .get_embedding_dim()
-                .map_err(|error| {
-                    tracing::error!(
-                        "Failed to get embedding dim: {:?}",
-                        error
-                    );
-                    error
-                })?;
-            if dimension < 1 {
-                return Err(anyhow::anyhow!(
-                    "Dimension is less than 1"
-                ));
-            }
-
-            Ok(dimension as u64)
-        })
+pub(crate) async fn download(config: &EmbeddingConfig) -> Result<PathBuf> {
+    let directory = config
+        .models_directory
+        .clone()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("embedding.models_directory is not set"))?;
+    let model = config.local_model;
+
+    tokio::task::spawn_blocking(move || local::download(model, &directory))
         .await
         .map_err(|error| {
             tracing::error!(
@@ -81,7 +119,61 @@ pub(crate) async fn get_dimension() -> Result<u64> {
                 error
             );
             error
-        })?;
+        })?
+}
+
+/// Starts the configured embedder, failing when it cannot embed.
+#[tracing::instrument(name = "vector_db.embeddings.start", err)]
+pub(crate) async fn start(
+    config: &EmbeddingConfig
+) -> Result<Arc<dyn Embedder>> {
+    let embedder: Arc<dyn Embedder> = match config.backend {
+        | EmbeddingBackend::Local => Arc::new(
+            LocalEmbedder::start(
+                config.local_model,
+                config
+                    .models_directory
+                    .as_ref()
+                    .map(PathBuf::from),
+            )
+            .await?,
+        ),
+        | EmbeddingBackend::Remote => Arc::new(
+            RemoteEmbedder::connect(
+                config.url.clone(),
+                config.model.clone(),
+                &config.api_key_env,
+            )
+            .await?,
+        ),
+        | EmbeddingBackend::Hashing => {
+            Arc::new(HashingEmbedder::new(config.dimension))
+        },
+    };
+
+    let embedder: Arc<dyn Embedder> = if config.cache_capacity > 0
+        || config
+            .cache_directory
+            .is_some()
+    {
+        Arc::new(CachedEmbedder::new(
+            embedder,
+            config.cache_capacity,
+            config
+                .cache_directory
+                .as_ref()
+                .map(PathBuf::from),
+            config.cache_directory_capacity,
+        )?)
+    } else {
+        embedder
+    };
+
+    tracing::info!(
+        "Embedding by {} with dimension {}",
+        embedder.model_id(),
+        embedder.dimension()
+    );
 
-    get_dimension_result
+    Ok(embedder)
 }
@@ -0,0 +1,574 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::vector_db::embeddings::Embedder;
+
+/// Hash of the model id and the text.
+type CacheKey = [u8; 32];
+
+/// Least recently used entries, embeddings in memory or files on disk.
+#[derive(Debug)]
+struct Lru<V> {
+    capacity: usize,
+    entries: HashMap<CacheKey, (V, u64)>,
+    /// Keys by the tick they were last used at, oldest first.
+    order: BTreeMap<u64, CacheKey>,
+    tick: u64,
+}
+
+impl<V: Clone> Lru<V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    fn get(
+        &mut self,
+        key: &CacheKey,
+    ) -> Option<V> {
+        self.tick += 1;
+        let (vector, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order
+            .insert(self.tick, *key);
+
+        Some(vector.clone())
+    }
+
+    /// Stores the entry, returning the keys evicted to stay in capacity.
+    fn put(
+        &mut self,
+        key: CacheKey,
+        value: V,
+    ) -> Vec<CacheKey> {
+        if self.capacity == 0 {
+            return vec![key];
+        }
+
+        self.tick += 1;
+        if let Some((_, used)) = self
+            .entries
+            .insert(key, (value, self.tick))
+        {
+            self.order.remove(&used);
+        }
+        self.order
+            .insert(self.tick, key);
+
+        let mut evicted = Vec::new();
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+            evicted.push(oldest);
+        }
+
+        evicted
+    }
+}
+
+/// Embedder remembering the embeddings of texts it has seen, in memory and
+/// optionally in a directory that survives restarts.
+#[derive(Debug)]
+pub(crate) struct CachedEmbedder {
+    inner: Arc<dyn Embedder>,
+    memory: Mutex<Lru<Vec<f32>>>,
+    /// One file of little-endian `f32`s per embedding, named by its key.
+    directory: Option<PathBuf>,
+    /// Files in the directory, the least recently used deleted beyond the
+    /// capacity.
+    files: Mutex<Lru<()>>,
+    hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CachedEmbedder {
+    /// Indexes the files already in the directory, oldest first.
+    pub(crate) fn new(
+        inner: Arc<dyn Embedder>,
+        capacity: usize,
+        directory: Option<PathBuf>,
+        directory_capacity: usize,
+    ) -> Result<Self> {
+        let mut files = Lru::new(directory_capacity);
+        let mut stale = Vec::new();
+        if let Some(directory) = &directory {
+            for (key, _) in scan(directory)? {
+                stale.extend(files.put(key, ()));
+            }
+        }
+
+        let embedder = Self {
+            inner,
+            memory: Mutex::new(Lru::new(capacity)),
+            directory,
+            files: Mutex::new(files),
+            hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        for key in stale {
+            if let Some(path) = embedder.path(&key) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+
+        Ok(embedder)
+    }
+
+    fn key(
+        &self,
+        sentence: &str,
+    ) -> CacheKey {
+        let mut hasher = Sha256::new();
+        hasher.update(
+            self.inner
+                .model_id()
+                .as_bytes(),
+        );
+        hasher.update([0]);
+        hasher.update(sentence.as_bytes());
+
+        hasher.finalize().into()
+    }
+
+    fn path(
+        &self,
+        key: &CacheKey,
+    ) -> Option<PathBuf> {
+        let name = key
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        self.directory
+            .as_ref()
+            .map(|directory| directory.join(name))
+    }
+
+    async fn read(
+        &self,
+        key: &CacheKey,
+    ) -> Option<Vec<f32>> {
+        let bytes = tokio::fs::read(self.path(key)?)
+            .await
+            .ok()?;
+        if bytes.len() as u64 != self.inner.dimension() * 4 {
+            return None;
+        }
+
+        Some(
+            bytes
+                .chunks_exact(4)
+                .map(|chunk| {
+                    f32::from_le_bytes([
+                        chunk[0], chunk[1], chunk[2], chunk[3],
+                    ])
+                })
+                .collect(),
+        )
+    }
+
+    async fn write(
+        &self,
+        key: &CacheKey,
+        vector: &[f32],
+    ) -> Result<()> {
+        let Some(path) = self.path(key) else {
+            return Ok(());
+        };
+        if let Some(directory) = path.parent() {
+            tokio::fs::create_dir_all(directory).await?;
+        }
+        let bytes = vector
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        tokio::fs::write(path, bytes).await?;
+
+        let evicted = self
+            .files
+            .lock()
+            .await
+            .put(*key, ());
+        for key in evicted {
+            if let Some(path) = self.path(&key) {
+                if let Err(error) = tokio::fs::remove_file(&path).await {
+                    tracing::warn!(
+                        "Failed to evict {}: {:?}",
+                        path.display(),
+                        error
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Keys of the files in the directory with their modification time, oldest
+/// first, skipping files not named by a key.
+fn scan(directory: &Path) -> Result<Vec<(CacheKey, SystemTime)>> {
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries = std::fs::read_dir(directory).map_err(|error| {
+        tracing::error!(
+            "Failed to read {}: {:?}",
+            directory.display(),
+            error
+        );
+        error
+    })?;
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let Some(key) = entry
+            .file_name()
+            .to_str()
+            .and_then(parse_key)
+        else {
+            continue;
+        };
+        let modified = entry
+            .metadata()?
+            .modified()
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        files.push((key, modified));
+    }
+    files.sort_by_key(|(_, modified)| *modified);
+
+    Ok(files)
+}
+
+fn parse_key(name: &str) -> Option<CacheKey> {
+    if name.len() != 64 {
+        return None;
+    }
+
+    let mut key = [0; 32];
+    for (byte, index) in key
+        .iter_mut()
+        .zip((0..64).step_by(2))
+    {
+        *byte = u8::from_str_radix(name.get(index..index + 2)?, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+#[tonic::async_trait]
+impl Embedder for CachedEmbedder {
+    fn model_id(&self) -> &str {
+        self.inner.model_id()
+    }
+
+    fn dimension(&self) -> u64 {
+        self.inner.dimension()
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.embeddings.cache.embed_batch",
+        err,
+        skip(self, sentences),
+        fields(count = sentences.len())
+    )]
+    async fn embed_batch(
+        &self,
+        sentences: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>> {
+        let keys = sentences
+            .iter()
+            .map(|sentence| self.key(sentence))
+            .collect::<Vec<_>>();
+
+        let mut vectors = {
+            let mut memory = self.memory.lock().await;
+            keys.iter()
+                .map(|key| memory.get(key))
+                .collect::<Vec<_>>()
+        };
+        let hits = vectors
+            .iter()
+            .filter(|vector| vector.is_some())
+            .count() as u64;
+
+        let mut disk_hits = 0;
+        if self.directory.is_some() {
+            for (vector, key) in vectors.iter_mut().zip(&keys) {
+                if vector.is_none() {
+                    *vector = self.read(key).await;
+                    if let Some(found) = vector {
+                        disk_hits += 1;
+                        self.files
+                            .lock()
+                            .await
+                            .get(key);
+                        self.memory
+                            .lock()
+                            .await
+                            .put(*key, found.clone());
+                    }
+                }
+            }
+        }
+
+        // Indexes of each missing text, so that repeated texts are embedded
+        // once.
+        let mut missing: Vec<(CacheKey, Vec<usize>)> = Vec::new();
+        for (index, vector) in vectors.iter().enumerate() {
+            if vector.is_some() {
+                continue;
+            }
+            match missing
+                .iter_mut()
+                .find(|(key, _)| key == &keys[index])
+            {
+                | Some((_, indexes)) => indexes.push(index),
+                | None => missing.push((keys[index], vec![index])),
+            }
+        }
+        if !missing.is_empty() {
+            let embedded = self
+                .inner
+                .embed_batch(
+                    missing
+                        .iter()
+                        .map(|(_, indexes)| sentences[indexes[0]].clone())
+                        .collect(),
+                )
+                .await?;
+            if embedded.len() != missing.len() {
+                return Err(anyhow!(
+                    "Expected {} embeddings but got {}",
+                    missing.len(),
+                    embedded.len()
+                ));
+            }
+
+            for ((key, indexes), vector) in missing.iter().zip(embedded) {
+                if let Err(error) = self.write(key, &vector).await {
+                    tracing::warn!(
+                        "Failed to write embedding cache: {:?}",
+                        error
+                    );
+                }
+                self.memory
+                    .lock()
+                    .await
+                    .put(*key, vector.clone());
+                for index in indexes {
+                    vectors[*index] = Some(vector.clone());
+                }
+            }
+        }
+
+        let misses = missing.len() as u64;
+        let total_hits = self
+            .hits
+            .fetch_add(hits, Ordering::Relaxed)
+            + hits;
+        let total_disk_hits = self
+            .disk_hits
+            .fetch_add(disk_hits, Ordering::Relaxed)
+            + disk_hits;
+        let total_misses = self
+            .misses
+            .fetch_add(misses, Ordering::Relaxed)
+            + misses;
+        tracing::debug!(
+            hits,
+            disk_hits,
+            misses,
+            total_hits,
+            total_disk_hits,
+            total_misses,
+            "Looked up embedding cache"
+        );
+
+        Ok(vectors
+            .into_iter()
+            .flatten()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::vector_db::embeddings::hashing::HashingEmbedder;
+
+    /// Counts the sentences embedded, dropping the last embedding when
+    /// asked to.
+    #[derive(Debug)]
+    struct Counting {
+        inner: HashingEmbedder,
+        embedded: AtomicUsize,
+        short: bool,
+    }
+
+    impl Counting {
+        fn new(short: bool) -> Arc<Self> {
+            Arc::new(Self {
+                inner: HashingEmbedder::new(4),
+                embedded: AtomicUsize::new(0),
+                short,
+            })
+        }
+    }
+
+    #[tonic::async_trait]
+    impl Embedder for Counting {
+        fn model_id(&self) -> &str {
+            self.inner.model_id()
+        }
+
+        fn dimension(&self) -> u64 {
+            self.inner.dimension()
+        }
+
+        async fn embed_batch(
+            &self,
+            sentences: Vec<String>,
+        ) -> Result<Vec<Vec<f32>>> {
+            self.embedded
+                .fetch_add(sentences.len(), Ordering::Relaxed);
+            let mut embeddings = self
+                .inner
+                .embed_batch(sentences)
+                .await?;
+            if self.short {
+                embeddings.pop();
+            }
+
+            Ok(embeddings)
+        }
+    }
+
+    fn sentences(texts: &[&str]) -> Vec<String> {
+        texts
+            .iter()
+            .map(|text| text.to_string())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn embed_batch_embeds_repeated_texts_once() {
+        let inner = Counting::new(false);
+        let cache = CachedEmbedder::new(inner.clone(), 8, None, 0).unwrap();
+
+        let embeddings = cache
+            .embed_batch(sentences(&["a", "b", "a"]))
+            .await
+            .unwrap();
+
+        assert_eq!(embeddings.len(), 3);
+        assert_eq!(embeddings[0], embeddings[2]);
+        assert_eq!(
+            inner
+                .embedded
+                .load(Ordering::Relaxed),
+            2
+        );
+
+        cache
+            .embed_batch(sentences(&["b", "a"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            inner
+                .embedded
+                .load(Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn embed_batch_rejects_missing_embeddings() {
+        let cache =
+            CachedEmbedder::new(Counting::new(true), 8, None, 0).unwrap();
+
+        assert!(cache
+            .embed_batch(sentences(&["a", "b"]))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn write_evicts_least_recently_used_files() {
+        let directory = std::env::temp_dir().join(format!(
+            "embedding_cache_{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let inner = Counting::new(false);
+        let cache = CachedEmbedder::new(
+            inner.clone(),
+            0,
+            Some(directory.clone()),
+            2,
+        )
+        .unwrap();
+
+        cache
+            .embed_batch(sentences(&["a", "b", "c"]))
+            .await
+            .unwrap();
+        let files = scan(&directory).unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(!files
+            .iter()
+            .any(|(key, _)| key == &cache.key("a")));
+
+        // Files left beyond a smaller capacity go on the next start.
+        let reopened = CachedEmbedder::new(
+            inner.clone(),
+            0,
+            Some(directory.clone()),
+            1,
+        )
+        .unwrap();
+        reopened
+            .embed_batch(sentences(&["b", "c"]))
+            .await
+            .unwrap();
+        assert_eq!(
+            scan(&directory)
+                .unwrap()
+                .len(),
+            1
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn parse_key_reads_file_names() {
+        let key = [0xab; 32];
+        let name = key
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+
+        assert_eq!(parse_key(&name), Some(key));
+        assert_eq!(parse_key("embedding.tmp"), None);
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::Result;
+
+use crate::vector_db::embeddings::Embedder;
+
+/// Embeds sentences by hashing their words into signed buckets, so that
+/// the same words always give the same vector without any model.
+#[derive(Debug)]
+pub(crate) struct HashingEmbedder {
+    dimension: u64,
+    model_id: String,
+}
+
+impl HashingEmbedder {
+    pub(crate) fn new(dimension: u64) -> Self {
+        Self {
+            dimension,
+            model_id: format!("hashing-{}", dimension),
+        }
+    }
+
+    fn embed_one(
+        &self,
+        sentence: &str,
+    ) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimension as usize];
+
+        let words = sentence
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(|word| word.to_lowercase())
+            .collect::<Vec<_>>();
+        // Keeps the vector non-zero for sentences without words.
+        let words = if words.is_empty() {
+            vec![String::new()]
+        } else {
+            words
+        };
+
+        for word in words {
+            let hash = fnv1a(word.as_bytes());
+            let index = (hash % self.dimension) as usize;
+            let sign = if hash >> 63 == 0 {
+                1.0
+            } else {
+                -1.0
+            };
+            vector[index] += sign;
+        }
+
+        let norm = vector
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>()
+            .sqrt();
+        if norm > 0.0 {
+            for value in &mut vector {
+                *value /= norm;
+            }
+        }
+
+        vector
+    }
+}
+
+#[tonic::async_trait]
+impl Embedder for HashingEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model_id
+    }
+
+    fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    async fn embed_batch(
+        &self,
+        sentences: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>> {
+        Ok(sentences
+            .iter()
+            .map(|sentence| self.embed_one(sentence))
+            .collect())
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds unlike the standard hasher.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn norm(vector: &[f32]) -> f32 {
+        vector
+            .iter()
+            .map(|value| value * value)
+            .sum::<f32>()
+            .sqrt()
+    }
+
+    #[tokio::test]
+    async fn embed_batch_is_deterministic() {
+        let embedder = HashingEmbedder::new(32);
+        let sentences = vec![
+            "Hello, world".to_string(),
+            "hello WORLD".to_string(),
+            "Good night".to_string(),
+        ];
+
+        let first = embedder
+            .embed_batch(sentences.clone())
+            .await
+            .unwrap();
+        let second = embedder
+            .embed_batch(sentences)
+            .await
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first[0], first[1]);
+        assert_ne!(first[0], first[2]);
+    }
+
+    #[tokio::test]
+    async fn embed_batch_gives_unit_vectors() {
+        let embedder = HashingEmbedder::new(16);
+
+        let embeddings = embedder
+            .embed_batch(vec![
+                "a b c".to_string(),
+                "".to_string(),
+                "!!!".to_string(),
+            ])
+            .await
+            .unwrap();
+
+        for vector in embeddings {
+            assert_eq!(vector.len(), 16);
+            assert!((norm(&vector) - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[tokio::test]
+    async fn embed_matches_embed_batch() {
+        let embedder = HashingEmbedder::new(8);
+
+        let single = embedder
+            .embed("one sentence".to_string())
+            .await
+            .unwrap();
+        let batch = embedder
+            .embed_batch(vec!["one sentence".to_string()])
+            .await
+            .unwrap();
+
+        assert_eq!(vec![single], batch);
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use rust_bert::{
+    pipelines::sentence_embeddings::{
+        SentenceEmbeddingsBuilder, SentenceEmbeddingsConfig,
+        SentenceEmbeddingsModel, SentenceEmbeddingsModelType,
+        SentenceEmbeddingsModulesConfig,
+    },
+    resources::ResourceProvider,
+    Config,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::vector_db::embeddings::Embedder;
+
+/// Upper bound of sentences encoded at once.
+const MAX_BATCH_SIZE: usize = 64;
+
+/// File naming the model of a downloaded bundle, written once it loads.
+const BUNDLE_FILE: &str = "bundle.json";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Bundle {
+    model: LocalModel,
+    dimension: u64,
+    /// SHA-256 in hex of each file by its path in the bundle, checked
+    /// before loading.
+    files: BTreeMap<String, String>,
+}
+
+/// Sentence embedding models of rust-bert, named as on Hugging Face.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LocalModel {
+    #[serde(rename = "all-MiniLM-L6-v2")]
+    AllMiniLmL6V2,
+    #[serde(rename = "all-MiniLM-L12-v2")]
+    AllMiniLmL12V2,
+    #[serde(rename = "all-distilroberta-v1")]
+    AllDistilrobertaV1,
+    #[serde(rename = "bert-base-nli-mean-tokens")]
+    BertBaseNliMeanTokens,
+    /// Supports 15 languages including Japanese.
+    #[serde(rename = "distiluse-base-multilingual-cased")]
+    DistiluseBaseMultilingualCased,
+    #[serde(rename = "paraphrase-albert-small-v2")]
+    ParaphraseAlbertSmallV2,
+    #[serde(rename = "sentence-t5-base")]
+    SentenceT5Base,
+}
+
+impl LocalModel {
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            | LocalModel::AllMiniLmL6V2 => "all-MiniLM-L6-v2",
+            | LocalModel::AllMiniLmL12V2 => "all-MiniLM-L12-v2",
+            | LocalModel::AllDistilrobertaV1 => "all-distilroberta-v1",
+            | LocalModel::BertBaseNliMeanTokens => "bert-base-nli-mean-tokens",
+            | LocalModel::DistiluseBaseMultilingualCased => {
+                "distiluse-base-multilingual-cased"
+            },
+            | LocalModel::ParaphraseAlbertSmallV2 => {
+                "paraphrase-albert-small-v2"
+            },
+            | LocalModel::SentenceT5Base => "sentence-t5-base",
+        }
+    }
+
+    /// Tokenizer vocabulary file expected by the local builder.
+    fn vocab_file(&self) -> &'static str {
+        match self {
+            | LocalModel::AllMiniLmL6V2
+            | LocalModel::AllMiniLmL12V2
+            | LocalModel::BertBaseNliMeanTokens
+            | LocalModel::DistiluseBaseMultilingualCased => "vocab.txt",
+            | LocalModel::AllDistilrobertaV1 => "vocab.json",
+            | LocalModel::ParaphraseAlbertSmallV2
+            | LocalModel::SentenceT5Base => "spiece.model",
+        }
+    }
+
+    fn model_type(&self) -> SentenceEmbeddingsModelType {
+        match self {
+            | LocalModel::AllMiniLmL6V2 => {
+                SentenceEmbeddingsModelType::AllMiniLmL6V2
+            },
+            | LocalModel::AllMiniLmL12V2 => {
+                SentenceEmbeddingsModelType::AllMiniLmL12V2
+            },
+            | LocalModel::AllDistilrobertaV1 => {
+                SentenceEmbeddingsModelType::AllDistilrobertaV1
+            },
+            | LocalModel::BertBaseNliMeanTokens => {
+                SentenceEmbeddingsModelType::BertBaseNliMeanTokens
+            },
+            | LocalModel::DistiluseBaseMultilingualCased => {
+                SentenceEmbeddingsModelType::DistiluseBaseMultilingualCased
+            },
+            | LocalModel::ParaphraseAlbertSmallV2 => {
+                SentenceEmbeddingsModelType::ParaphraseAlbertSmallV2
+            },
+            | LocalModel::SentenceT5Base => {
+                SentenceEmbeddingsModelType::SentenceT5Base
+            },
+        }
+    }
+}
+
+struct EmbeddingRequest {
+    sentences: Vec<String>,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>>>,
+}
+
+/// Sentence embedding model loaded once on a dedicated thread.
+///
+/// Requests are split into chunks of at most `MAX_BATCH_SIZE` sentences, and
+/// chunks arriving while the model is encoding are batched into the next
+/// `encode` call.
+#[derive(Debug)]
+pub(crate) struct LocalEmbedder {
+    sender: mpsc::UnboundedSender<EmbeddingRequest>,
+    model: LocalModel,
+    dimension: u64,
+}
+
+impl LocalEmbedder {
+    /// Starts the worker thread and waits until the model is loaded, from
+    /// `<directory>/<model name>` when given or else downloaded to the
+    /// rust-bert cache.
+    #[tracing::instrument(
+        name = "vector_db.embeddings.local.start",
+        err
+    )]
+    pub(crate) async fn start(
+        model: LocalModel,
+        directory: Option<PathBuf>,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (ready_sender, ready_receiver) = oneshot::channel();
+
+        std::thread::Builder::new()
+            .name("embeddings".to_string())
+            .spawn(move || run(model, directory, receiver, ready_sender))
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to spawn embedding thread: {:?}",
+                    error
+                );
+                error
+            })?;
+
+        let dimension = ready_receiver
+            .await
+            .map_err(|_| anyhow!("Embedding thread stopped while loading"))??;
+
+        tracing::info!(
+            "Loaded embedding model {} with dimension {}",
+            model.name(),
+            dimension
+        );
+
+        Ok(Self {
+            sender,
+            model,
+            dimension,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Embedder for LocalEmbedder {
+    fn model_id(&self) -> &str {
+        self.model.name()
+    }
+
+    fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.embeddings.local.embed_batch",
+        err,
+        skip(self, sentences),
+        fields(count = sentences.len())
+    )]
+    async fn embed_batch(
+        &self,
+        sentences: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>> {
+        if sentences.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Queues every chunk first so the worker encodes them back to back.
+        let mut receivers = Vec::new();
+        for chunk in sentences.chunks(MAX_BATCH_SIZE) {
+            let (reply, receiver) = oneshot::channel();
+            self.sender
+                .send(EmbeddingRequest {
+                    sentences: chunk.to_vec(),
+                    reply,
+                })
+                .map_err(|_| anyhow!("Embedding thread stopped"))?;
+            receivers.push(receiver);
+        }
+
+        let mut embeddings = Vec::with_capacity(sentences.len());
+        for receiver in receivers {
+            embeddings.extend(receiver.await.map_err(|_| {
+                anyhow!("Embedding thread dropped a request")
+            })??);
+        }
+
+        Ok(embeddings)
+    }
+}
+
+/// Downloads the model into `<directory>/<model name>` in the layout of
+/// rust-bert's local builder and checks that it loads and embeds. Blocks on
+/// the network.
+pub(crate) fn download(
+    model: LocalModel,
+    directory: &Path,
+) -> Result<PathBuf> {
+    let target = directory.join(model.name());
+    let config = SentenceEmbeddingsConfig::from(model.model_type());
+
+    let modules = SentenceEmbeddingsModulesConfig::from_file(
+        config
+            .modules_config_resource
+            .get_local_path()?,
+    )
+    .validate()?;
+    let pooling = PathBuf::from(&modules.pooling_module().path);
+    let dense = modules
+        .dense_module()
+        .map(|module| PathBuf::from(&module.path));
+
+    let mut files = vec![
+        (
+            &config.modules_config_resource,
+            PathBuf::from("modules.json"),
+        ),
+        (
+            &config.transformer_config_resource,
+            PathBuf::from("config.json"),
+        ),
+        (
+            &config.transformer_weights_resource,
+            PathBuf::from("rust_model.ot"),
+        ),
+        (
+            &config.pooling_config_resource,
+            pooling.join("config.json"),
+        ),
+        (
+            &config.sentence_bert_config_resource,
+            PathBuf::from("sentence_bert_config.json"),
+        ),
+        (
+            &config.tokenizer_config_resource,
+            PathBuf::from("tokenizer_config.json"),
+        ),
+        (
+            &config.tokenizer_vocab_resource,
+            PathBuf::from(model.vocab_file()),
+        ),
+    ];
+    if let Some(merges) = &config.tokenizer_merges_resource {
+        files.push((merges, PathBuf::from("merges.txt")));
+    }
+    if let (Some(dense), Some(dense_config), Some(dense_weights)) = (
+        &dense,
+        &config.dense_config_resource,
+        &config.dense_weights_resource,
+    ) {
+        files.push((dense_config, dense.join("config.json")));
+        files.push((
+            dense_weights,
+            dense.join("rust_model.ot"),
+        ));
+    }
+
+    let mut hashes = BTreeMap::new();
+    for (resource, name) in files {
+        let source = resource.get_local_path()?;
+        let destination = target.join(&name);
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&source, &destination).map_err(|error| {
+            tracing::error!(
+                "Failed to copy {} to {}: {:?}",
+                source.display(),
+                destination.display(),
+                error
+            );
+            error
+        })?;
+        hashes.insert(
+            name.to_string_lossy()
+                .into_owned(),
+            sha256(&destination)?,
+        );
+        tracing::info!("Downloaded {}", destination.display());
+    }
+
+    let (loaded, dimension) = load_from(&target)?;
+    let probe = loaded.encode(&["verification"])?;
+    if probe
+        .first()
+        .map(|vector| vector.len() as u64)
+        != Some(dimension)
+    {
+        return Err(anyhow!(
+            "Model {} in {} does not embed",
+            model.name(),
+            target.display()
+        ));
+    }
+
+    std::fs::write(
+        target.join(BUNDLE_FILE),
+        serde_json::to_string_pretty(&Bundle {
+            model,
+            dimension,
+            files: hashes,
+        })?,
+    )?;
+
+    Ok(target)
+}
+
+fn load_model(
+    model: LocalModel,
+    directory: Option<&Path>,
+) -> Result<(SentenceEmbeddingsModel, u64)> {
+    let Some(directory) = directory else {
+        return with_dimension(
+            SentenceEmbeddingsBuilder::remote(model.model_type())
+                .create_model(),
+        );
+    };
+
+    let path = directory.join(model.name());
+    let bundle_path = path.join(BUNDLE_FILE);
+    if !bundle_path.exists() {
+        return Err(anyhow!(
+            "Model {} is not downloaded to {}, run the download-model \
+             command first",
+            model.name(),
+            directory.display()
+        ));
+    }
+
+    let text = std::fs::read_to_string(&bundle_path).map_err(|error| {
+        tracing::error!(
+            "Failed to read {}: {:?}",
+            bundle_path.display(),
+            error
+        );
+        error
+    })?;
+    let bundle = serde_json::from_str::<Bundle>(&text).map_err(|error| {
+        tracing::error!(
+            "Failed to parse {}: {:?}",
+            bundle_path.display(),
+            error
+        );
+        anyhow!(
+            "Bundle {} is invalid, run the download-model command again: {}",
+            bundle_path.display(),
+            error
+        )
+    })?;
+    if bundle.model != model {
+        return Err(anyhow!(
+            "Bundle {} holds {} instead of {}",
+            bundle_path.display(),
+            bundle.model.name(),
+            model.name()
+        ));
+    }
+
+    for (name, expected) in &bundle.files {
+        let file = path.join(name);
+        if &sha256(&file)? != expected {
+            return Err(anyhow!(
+                "File {} of model {} is corrupted, run the download-model \
+                 command again",
+                file.display(),
+                model.name()
+            ));
+        }
+    }
+
+    load_from(&path)
+}
+
+/// SHA-256 of the file in hex.
+fn sha256(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path).map_err(|error| {
+        tracing::error!(
+            "Failed to open {}: {:?}",
+            path.display(),
+            error
+        );
+        error
+    })?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn load_from(path: &Path) -> Result<(SentenceEmbeddingsModel, u64)> {
+    with_dimension(SentenceEmbeddingsBuilder::local(path).create_model())
+}
+
+fn with_dimension(
+    model: Result<SentenceEmbeddingsModel, rust_bert::RustBertError>
+) -> Result<(SentenceEmbeddingsModel, u64)> {
+    let model = model.map_err(|error| {
+        tracing::error!("Failed to create model: {:?}", error);
+        error
+    })?;
+
+    let dimension = model
+        .get_embedding_dim()
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to get embedding dim: {:?}",
+                error
+            );
+            error
+        })?;
+    if dimension < 1 {
+        return Err(anyhow!("Dimension is less than 1"));
+    }
+
+    Ok((model, dimension as u64))
+}
+
+/// Serves requests until every sender is dropped.
+fn run(
+    model: LocalModel,
+    directory: Option<PathBuf>,
+    mut receiver: mpsc::UnboundedReceiver<EmbeddingRequest>,
+    ready: oneshot::Sender<Result<u64>>,
+) {
+    let model = match load_model(model, directory.as_deref()) {
+        | Ok((model, dimension)) => {
+            if ready
+                .send(Ok(dimension))
+                .is_err()
+            {
+                return;
+            }
+            model
+        },
+        | Err(error) => {
+            let _ = ready.send(Err(error));
+            return;
+        },
+    };
+
+    while let Some(first) = receiver.blocking_recv() {
+        let mut count = first.sentences.len();
+        let mut requests = vec![first];
+        while count < MAX_BATCH_SIZE {
+            match receiver.try_recv() {
+                | Ok(request) => {
+                    count += request.sentences.len();
+                    requests.push(request);
+                },
+                | Err(_) => break,
+            }
+        }
+
+        let sentences = requests
+            .iter()
+            .flat_map(|request| request.sentences.iter())
+            .collect::<Vec<_>>();
+        tracing::debug!(
+            "Encoding {} sentences of {} requests",
+            sentences.len(),
+            requests.len()
+        );
+
+        match model.encode(&sentences) {
+            | Ok(embeddings) => {
+                let mut embeddings = embeddings.into_iter();
+                for request in requests {
+                    let embedding = embeddings
+                        .by_ref()
+                        .take(request.sentences.len())
+                        .collect();
+                    let _ = request
+                        .reply
+                        .send(Ok(embedding));
+                }
+            },
+            | Err(error) => {
+                tracing::error!(
+                    "Failed to encode sentences: {:?}",
+                    error
+                );
+                for request in requests {
+                    let _ = request
+                        .reply
+                        .send(Err(anyhow!(
+                            "Failed to encode sentences: {}",
+                            error
+                        )));
+                }
+            },
+        }
+    }
+
+    tracing::info!("Embedding thread stopped");
+}
@@ -0,0 +1,247 @@
+use std::env;
+
+use anyhow::{anyhow, Result};
+use hyper::{client::HttpConnector, Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+
+use crate::vector_db::embeddings::Embedder;
+
+/// Upper bound of sentences sent in one request.
+const MAX_BATCH_SIZE: usize = 512;
+
+#[derive(Serialize, Debug)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize, Debug)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+/// Embeds sentences by an OpenAI compatible `/v1/embeddings` API.
+pub(crate) struct RemoteEmbedder {
+    client: Client<HttpsConnector<HttpConnector>>,
+    url: hyper::Uri,
+    model: String,
+    api_key: String,
+    dimension: u64,
+}
+
+impl std::fmt::Debug for RemoteEmbedder {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("RemoteEmbedder")
+            .field("url", &self.url)
+            .field("model", &self.model)
+            .field("dimension", &self.dimension)
+            .finish()
+    }
+}
+
+impl RemoteEmbedder {
+    /// Embeds a probe sentence to check the API and learn the dimension.
+    pub(crate) async fn connect(
+        url: String,
+        model: String,
+        api_key_env: &str,
+    ) -> Result<Self> {
+        let api_key = env::var(api_key_env).map_err(|error| {
+            tracing::error!(
+                "Failed to get {}: {:?}",
+                api_key_env,
+                error
+            );
+            error
+        })?;
+
+        let url = url
+            .parse::<hyper::Uri>()
+            .map_err(|error| {
+                tracing::error!("Failed to parse URI: {:?}", error);
+                error
+            })?;
+
+        let mut embedder = Self {
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            url,
+            model,
+            api_key,
+            dimension: 0,
+        };
+
+        let probe = embedder
+            .request(&["dimension".to_string()])
+            .await?;
+        embedder.dimension = probe
+            .first()
+            .map(|vector| vector.len() as u64)
+            .filter(|dimension| *dimension > 0)
+            .ok_or_else(|| anyhow!("Dimension is less than 1"))?;
+
+        Ok(embedder)
+    }
+
+    async fn request(
+        &self,
+        sentences: &[String],
+    ) -> Result<Vec<Vec<f32>>> {
+        let json_str = serde_json::to_string(&EmbeddingsRequest {
+            model: &self.model,
+            input: sentences,
+        })
+        .map_err(|error| {
+            tracing::error!("Failed to serialize JSON: {:?}", error);
+            error
+        })?;
+
+        let request = Request::post(self.url.clone())
+            .header(
+                "Authorization",
+                "Bearer ".to_owned() + &self.api_key,
+            )
+            .header("Content-Type", "application/json")
+            .body(Body::from(json_str))
+            .map_err(|error| {
+                tracing::error!("Failed to create request: {:?}", error);
+                error
+            })?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to make request: {:?}", error);
+                error
+            })?;
+
+        let status = response.status();
+        let body_bytes = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to read response body: {:?}",
+                    error
+                );
+                error
+            })?;
+
+        if !status.is_success() {
+            let error = anyhow!(
+                "HTTP request failed: {}\nResponse body: {}",
+                status,
+                String::from_utf8_lossy(&body_bytes)
+            );
+            tracing::error!("{:?}", error);
+            return Err(error);
+        }
+
+        parse_embeddings(&body_bytes, sentences.len())
+    }
+}
+
+/// Embeddings of the response body in the order of the input sentences.
+fn parse_embeddings(
+    body: &[u8],
+    count: usize,
+) -> Result<Vec<Vec<f32>>> {
+    let mut response = serde_json::from_slice::<EmbeddingsResponse>(body)
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to deserialize JSON: {:?}",
+                error
+            );
+            error
+        })?;
+    if response.data.len() != count {
+        return Err(anyhow!(
+            "Expected {} embeddings but got {}",
+            count,
+            response.data.len()
+        ));
+    }
+    response
+        .data
+        .sort_by_key(|data| data.index);
+
+    Ok(response
+        .data
+        .into_iter()
+        .map(|data| data.embedding)
+        .collect())
+}
+
+#[tonic::async_trait]
+impl Embedder for RemoteEmbedder {
+    fn model_id(&self) -> &str {
+        &self.model
+    }
+
+    fn dimension(&self) -> u64 {
+        self.dimension
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.embeddings.remote.embed_batch",
+        err,
+        skip(self, sentences),
+        fields(count = sentences.len())
+    )]
+    async fn embed_batch(
+        &self,
+        sentences: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>> {
+        let mut embeddings = Vec::with_capacity(sentences.len());
+        for chunk in sentences.chunks(MAX_BATCH_SIZE) {
+            embeddings.extend(self.request(chunk).await?);
+        }
+
+        Ok(embeddings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_embeddings_orders_by_index() {
+        let body = br#"{"data": [
+            {"index": 1, "embedding": [0.0, 1.0]},
+            {"index": 0, "embedding": [1.0, 0.0]}
+        ]}"#;
+
+        let embeddings = parse_embeddings(body, 2).unwrap();
+
+        assert_eq!(
+            embeddings,
+            vec![
+                vec![1.0, 0.0],
+                vec![0.0, 1.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_embeddings_rejects_missing_embeddings() {
+        let body = br#"{"data": [{"index": 0, "embedding": [1.0]}]}"#;
+
+        assert!(parse_embeddings(body, 2).is_err());
+    }
+
+    #[test]
+    fn parse_embeddings_rejects_invalid_body() {
+        assert!(parse_embeddings(b"{}", 1).is_err());
+    }
+}
@@ -0,0 +1,423 @@
+pub(super) mod memory;
+pub(super) mod qdrant;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use qdrant_client::prelude::QdrantClient;
+use serde::{Deserialize, Serialize};
+
+use crate::vector_db::embeddings::Embedder;
+use crate::vector_db::store::{memory::MemoryStore, qdrant::QdrantStore};
+
+/// Fields stored with a vector.
+pub(crate) type Payload = serde_json::Map<String, serde_json::Value>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct StoredPoint {
+    pub(crate) id: String,
+    pub(crate) vector: Vec<f32>,
+    pub(crate) payload: Payload,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ScoredPoint {
+    pub(crate) vector: Vec<f32>,
+    /// Cosine similarity to the query.
+    pub(crate) score: f32,
+    pub(crate) payload: Payload,
+}
+
+/// Conditions points must meet, none meaning every point.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filter {
+    /// Conditions every point meets.
+    pub(crate) must: Vec<Condition>,
+    /// Conditions a point meets at least one of, unless there are none.
+    pub(crate) should: Vec<Condition>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Condition {
+    /// A string field of the payload equals the value.
+    Equals {
+        key: String,
+        value: String,
+    },
+    /// A numeric field of the payload is within the inclusive bounds.
+    Range {
+        key: String,
+        gte: Option<f64>,
+        lte: Option<f64>,
+    },
+}
+
+/// Type of a payload field indexed for filtering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FieldKind {
+    Keyword,
+    Integer,
+}
+
+impl Filter {
+    pub(crate) fn matches(
+        &self,
+        payload: &Payload,
+    ) -> bool {
+        self.must
+            .iter()
+            .all(|condition| condition.matches(payload))
+            && (self.should.is_empty()
+                || self
+                    .should
+                    .iter()
+                    .any(|condition| condition.matches(payload)))
+    }
+}
+
+impl Condition {
+    pub(crate) fn equals(
+        key: &str,
+        value: String,
+    ) -> Self {
+        Condition::Equals {
+            key: key.to_string(),
+            value,
+        }
+    }
+
+    pub(crate) fn range(
+        key: &str,
+        gte: Option<f64>,
+        lte: Option<f64>,
+    ) -> Self {
+        Condition::Range {
+            key: key.to_string(),
+            gte,
+            lte,
+        }
+    }
+
+    fn matches(
+        &self,
+        payload: &Payload,
+    ) -> bool {
+        match self {
+            | Condition::Equals {
+                key,
+                value,
+            } => {
+                payload
+                    .get(key)
+                    .and_then(|field| field.as_str())
+                    == Some(value.as_str())
+            },
+            | Condition::Range {
+                key,
+                gte,
+                lte,
+            } => match payload
+                .get(key)
+                .and_then(|field| field.as_f64())
+            {
+                | Some(field) => {
+                    gte.iter()
+                        .all(|gte| field >= *gte)
+                        && lte
+                            .iter()
+                            .all(|lte| field <= *lte)
+                },
+                | None => false,
+            },
+        }
+    }
+}
+
+/// Collection of vectors with payloads, searched by cosine similarity.
+#[tonic::async_trait]
+pub(crate) trait VectorStore: Send + Sync + std::fmt::Debug {
+    /// Name of the collection.
+    fn name(&self) -> &str;
+
+    async fn upsert(
+        &self,
+        points: Vec<StoredPoint>,
+    ) -> Result<()>;
+
+    /// Most similar points, best first.
+    async fn search(
+        &self,
+        vector: Vec<f32>,
+        limit: u64,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>>;
+
+    async fn delete(
+        &self,
+        filter: &Filter,
+    ) -> Result<()>;
+
+    /// Points in a stable order from `offset`, with the offset of the next
+    /// page when there is one.
+    async fn scroll(
+        &self,
+        filter: &Filter,
+        offset: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<StoredPoint>, Option<String>)>;
+
+    async fn count(
+        &self,
+        filter: &Filter,
+    ) -> Result<u64>;
+
+    /// Releases the collection, e.g. deleting an ephemeral one or writing
+    /// a snapshot.
+    async fn close(&self) -> Result<()>;
+}
+
+/// How long memories live.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum MemoryMode {
+    /// Keeps memories across restarts.
+    Persistent,
+    /// Deletes memories on start.
+    Reset,
+    /// Keeps memories only for this run.
+    Ephemeral,
+}
+
+/// What to do with a collection made for another embedding model.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum OnMismatch {
+    /// Refuses to start.
+    Fail,
+    /// Embeds the stored texts again by the current model.
+    Reembed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum StoreBackend {
+    Qdrant,
+    /// Brute-force search in this process, needing no service.
+    Memory,
+}
+
+/// Where and how long memories are stored.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct VectorStoreConfig {
+    pub(crate) backend: StoreBackend,
+    pub(crate) mode: MemoryMode,
+    pub(crate) on_mismatch: OnMismatch,
+    /// Directory the memory backend writes its collections to on shutdown
+    /// and reads them from on start.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) snapshot_directory: Option<String>,
+}
+
+impl Default for VectorStoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: StoreBackend::Qdrant,
+            mode: MemoryMode::Persistent,
+            on_mismatch: OnMismatch::Fail,
+            snapshot_directory: None,
+        }
+    }
+}
+
+/// Backend shared by the collections of every creature.
+#[derive(Clone)]
+pub(crate) enum Connection {
+    Qdrant(Arc<QdrantClient>),
+    Memory,
+}
+
+impl Connection {
+    /// Opens the collection for vectors of the embedder, indexing the
+    /// payload fields when the backend supports it.
+    pub(crate) async fn open(
+        &self,
+        name: &str,
+        embedder: &dyn Embedder,
+        config: &VectorStoreConfig,
+        indexes: &[(&str, FieldKind)],
+    ) -> Result<Arc<dyn VectorStore>> {
+        let store: Arc<dyn VectorStore> = match self {
+            | Connection::Qdrant(client) => Arc::new(
+                QdrantStore::open(
+                    client.clone(),
+                    name.to_string(),
+                    embedder,
+                    config.mode,
+                    config.on_mismatch,
+                    indexes,
+                )
+                .await?,
+            ),
+            | Connection::Memory => Arc::new(
+                MemoryStore::open(
+                    name.to_string(),
+                    embedder,
+                    config.mode,
+                    config.on_mismatch,
+                    config
+                        .snapshot_directory
+                        .as_ref()
+                        .map(PathBuf::from),
+                )
+                .await?,
+            ),
+        };
+
+        tracing::info!(
+            "Collection {} holds {} memories",
+            store.name(),
+            store
+                .count(&Filter::default())
+                .await?
+        );
+
+        Ok(store)
+    }
+}
+
+/// Model id usable in a collection name, e.g. `all-MiniLM-L6-v2`.
+pub(super) fn model_tag(model_id: &str) -> String {
+    model_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Text of the stored points, for embedding them again.
+pub(crate) fn texts(points: &[StoredPoint]) -> Result<Vec<String>> {
+    points
+        .iter()
+        .map(|point| {
+            point
+                .payload
+                .get("text")
+                .and_then(|text| text.as_str())
+                .map(|text| text.to_string())
+                .ok_or_else(|| {
+                    anyhow::anyhow!("Point {} has no text", point.id)
+                })
+        })
+        .collect()
+}
+
+/// Cosine similarity of two vectors, zero when either is zero.
+pub(crate) fn cosine_similarity(
+    a: &[f32],
+    b: &[f32],
+) -> f32 {
+    let dot = a
+        .iter()
+        .zip(b)
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    let norm_a = a
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt();
+    let norm_b = b
+        .iter()
+        .map(|x| x * x)
+        .sum::<f32>()
+        .sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn payload(value: serde_json::Value) -> Payload {
+        value
+            .as_object()
+            .unwrap()
+            .clone()
+    }
+
+    #[test]
+    fn filter_matches_every_point_without_conditions() {
+        assert!(Filter::default().matches(&payload(json!({}))));
+    }
+
+    #[test]
+    fn filter_requires_every_must_condition() {
+        let filter = Filter {
+            must: vec![
+                Condition::equals("author", "alice".to_string()),
+                Condition::equals("kind", "speech".to_string()),
+            ],
+            should: Vec::new(),
+        };
+
+        assert!(filter.matches(&payload(
+            json!({"author": "alice", "kind": "speech"})
+        )));
+        assert!(!filter.matches(&payload(
+            json!({"author": "alice", "kind": "reaction"})
+        )));
+        assert!(!filter.matches(&payload(json!({"author": "alice"}))));
+    }
+
+    #[test]
+    fn filter_requires_one_should_condition() {
+        let filter = Filter {
+            must: Vec::new(),
+            should: vec![
+                Condition::equals("author", "alice".to_string()),
+                Condition::equals("visibility", "public".to_string()),
+            ],
+        };
+
+        assert!(filter.matches(&payload(json!({"author": "alice"}))));
+        assert!(filter.matches(&payload(
+            json!({"author": "bob", "visibility": "public"})
+        )));
+        assert!(!filter.matches(&payload(json!({"author": "bob"}))));
+    }
+
+    #[test]
+    fn range_is_inclusive_and_needs_a_number() {
+        let filter = Filter {
+            must: vec![Condition::range(
+                "timestamp",
+                Some(10.0),
+                Some(20.0),
+            )],
+            should: Vec::new(),
+        };
+
+        assert!(filter.matches(&payload(json!({"timestamp": 10}))));
+        assert!(filter.matches(&payload(json!({"timestamp": 20.0}))));
+        assert!(!filter.matches(&payload(json!({"timestamp": 21}))));
+        assert!(!filter.matches(&payload(json!({"timestamp": "15"}))));
+        assert!(!filter.matches(&payload(json!({}))));
+
+        let open = Condition::range("timestamp", None, Some(20.0));
+        assert!(open.matches(&payload(json!({"timestamp": -5}))));
+    }
+}
@@ -0,0 +1,481 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::creature::storage::{load_json, save_json};
+use crate::vector_db::embeddings::Embedder;
+use crate::vector_db::store::{
+    self, cosine_similarity, Filter, MemoryMode, OnMismatch, ScoredPoint,
+    StoredPoint, VectorStore,
+};
+
+/// Points written on close with the embedding model of their vectors.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    /// None in snapshots of an unknown model.
+    #[serde(default)]
+    model: Option<String>,
+    points: Vec<StoredPoint>,
+}
+
+/// Collection held in this process and searched by brute force, for running
+/// without a Qdrant server.
+#[derive(Debug)]
+pub(crate) struct MemoryStore {
+    name: String,
+    model: String,
+    /// Points by id, so that scrolling follows a stable order.
+    points: Mutex<BTreeMap<String, StoredPoint>>,
+    /// File the points are written to on close, none when they only live
+    /// for this run.
+    snapshot: Option<PathBuf>,
+}
+
+impl MemoryStore {
+    /// Opens the collection in the mode, reading the snapshot written on the
+    /// last shutdown when the memories persist.
+    pub(crate) async fn open(
+        name: String,
+        embedder: &dyn Embedder,
+        mode: MemoryMode,
+        on_mismatch: OnMismatch,
+        snapshot_directory: Option<PathBuf>,
+    ) -> Result<Self> {
+        let snapshot = match mode {
+            | MemoryMode::Ephemeral => None,
+            | _ => snapshot_directory
+                .map(|directory| directory.join(format!("{}.json", name))),
+        };
+
+        let Snapshot {
+            model,
+            mut points,
+        } = match (&snapshot, mode) {
+            | (Some(path), MemoryMode::Persistent) => load_json(path)?,
+            | _ => Snapshot::default(),
+        };
+
+        let dimension = embedder.dimension() as usize;
+        let mismatch = match model {
+            | Some(model) if model != embedder.model_id() => Some(format!(
+                "it was made for the model {} instead of {}",
+                model,
+                embedder.model_id()
+            )),
+            | _ => points
+                .iter()
+                .find(|point| point.vector.len() != dimension)
+                .map(|point| {
+                    format!(
+                        "vector size is {} instead of {}",
+                        point.vector.len(),
+                        dimension
+                    )
+                }),
+        };
+        if let Some(mismatch) = mismatch {
+            match on_mismatch {
+                | OnMismatch::Fail => {
+                    return Err(anyhow!(
+                        "Collection {} does not fit the embedding model {}: {}. \
+                         Set vector_store.on_mismatch = \"reembed\" to migrate \
+                         it or vector_store.mode = \"reset\" to discard it",
+                        name,
+                        embedder.model_id(),
+                        mismatch
+                    ));
+                },
+                | OnMismatch::Reembed => {
+                    tracing::warn!(
+                        "Re-embedding collection {}: {}",
+                        name,
+                        mismatch
+                    );
+                    let vectors = embedder
+                        .embed_batch(store::texts(&points)?)
+                        .await?;
+                    for (point, vector) in points.iter_mut().zip(vectors) {
+                        point.vector = vector;
+                    }
+                },
+            }
+        }
+
+        tracing::info!(
+            "Opened collection {} in memory with {} points in {:?} mode",
+            name,
+            points.len(),
+            mode
+        );
+
+        Ok(Self {
+            name,
+            model: embedder
+                .model_id()
+                .to_string(),
+            points: Mutex::new(
+                points
+                    .into_iter()
+                    .map(|point| (point.id.clone(), point))
+                    .collect(),
+            ),
+            snapshot,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl VectorStore for MemoryStore {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn upsert(
+        &self,
+        points: Vec<StoredPoint>,
+    ) -> Result<()> {
+        let mut stored = self.points.lock().await;
+        for point in points {
+            stored.insert(point.id.clone(), point);
+        }
+
+        Ok(())
+    }
+
+    async fn search(
+        &self,
+        vector: Vec<f32>,
+        limit: u64,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>> {
+        let stored = self.points.lock().await;
+        let mut scored = stored
+            .values()
+            .filter(|point| filter.matches(&point.payload))
+            .map(|point| ScoredPoint {
+                vector: point.vector.clone(),
+                score: cosine_similarity(&vector, &point.vector),
+                payload: point.payload.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        scored.sort_by(|a, b| b.score.total_cmp(&a.score));
+        scored.truncate(limit as usize);
+
+        Ok(scored)
+    }
+
+    async fn delete(
+        &self,
+        filter: &Filter,
+    ) -> Result<()> {
+        self.points
+            .lock()
+            .await
+            .retain(|_, point| !filter.matches(&point.payload));
+
+        Ok(())
+    }
+
+    async fn scroll(
+        &self,
+        filter: &Filter,
+        offset: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<StoredPoint>, Option<String>)> {
+        let stored = self.points.lock().await;
+        let start = match offset {
+            | Some(offset) => Bound::Included(offset),
+            | None => Bound::Unbounded,
+        };
+
+        let mut matching = stored
+            .range((start, Bound::Unbounded))
+            .map(|(_, point)| point)
+            .filter(|point| filter.matches(&point.payload));
+        let page = matching
+            .by_ref()
+            .take(limit as usize)
+            .cloned()
+            .collect();
+        let next = matching
+            .next()
+            .map(|point| point.id.clone());
+
+        Ok((page, next))
+    }
+
+    async fn count(
+        &self,
+        filter: &Filter,
+    ) -> Result<u64> {
+        Ok(self
+            .points
+            .lock()
+            .await
+            .values()
+            .filter(|point| filter.matches(&point.payload))
+            .count() as u64)
+    }
+
+    /// Writes the snapshot when the memories persist.
+    async fn close(&self) -> Result<()> {
+        let Some(path) = &self.snapshot else {
+            return Ok(());
+        };
+
+        let points = self
+            .points
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let count = points.len();
+        save_json(
+            path,
+            &Snapshot {
+                model: Some(self.model.clone()),
+                points,
+            },
+        )
+        .await?;
+
+        tracing::info!(
+            "Saved {} points of {} to {}",
+            count,
+            self.name,
+            path.display()
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::vector_db::embeddings::hashing::HashingEmbedder;
+    use crate::vector_db::store::Condition;
+
+    fn point(
+        id: &str,
+        vector: Vec<f32>,
+        author: &str,
+    ) -> StoredPoint {
+        StoredPoint {
+            id: id.to_string(),
+            vector,
+            payload: json!({"text": id, "author": author})
+                .as_object()
+                .unwrap()
+                .clone(),
+        }
+    }
+
+    fn by_author(author: &str) -> Filter {
+        Filter {
+            must: vec![Condition::equals(
+                "author",
+                author.to_string(),
+            )],
+            should: Vec::new(),
+        }
+    }
+
+    async fn open(
+        mode: MemoryMode,
+        directory: Option<PathBuf>,
+    ) -> MemoryStore {
+        MemoryStore::open(
+            "memories".to_string(),
+            &HashingEmbedder::new(2),
+            mode,
+            OnMismatch::Fail,
+            directory,
+        )
+        .await
+        .unwrap()
+    }
+
+    async fn filled() -> MemoryStore {
+        let store = open(MemoryMode::Ephemeral, None).await;
+        store
+            .upsert(vec![
+                point("a", vec![1.0, 0.0], "alice"),
+                point("b", vec![0.0, 1.0], "bob"),
+                point("c", vec![1.0, 1.0], "alice"),
+                point("d", vec![-1.0, 0.0], "alice"),
+            ])
+            .await
+            .unwrap();
+
+        store
+    }
+
+    #[tokio::test]
+    async fn search_orders_by_similarity_and_truncates() {
+        let store = filled().await;
+
+        let found = store
+            .search(vec![1.0, 0.0], 2, &Filter::default())
+            .await
+            .unwrap();
+
+        let texts = found
+            .iter()
+            .map(|point| {
+                point.payload["text"]
+                    .as_str()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(texts, vec!["a", "c"]);
+        assert!(found[0].score > found[1].score);
+    }
+
+    #[tokio::test]
+    async fn search_applies_the_filter() {
+        let store = filled().await;
+
+        let found = store
+            .search(vec![1.0, 0.0], 10, &by_author("bob"))
+            .await
+            .unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].payload["text"], "b");
+    }
+
+    #[tokio::test]
+    async fn scroll_pages_through_matching_points() {
+        let store = filled().await;
+        let filter = by_author("alice");
+
+        let (page, next) = store
+            .scroll(&filter, None, 2)
+            .await
+            .unwrap();
+        let ids = page
+            .iter()
+            .map(|point| point.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec!["a", "c"]);
+        assert_eq!(next.as_deref(), Some("d"));
+
+        let (page, next) = store
+            .scroll(&filter, next, 2)
+            .await
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, "d");
+        assert_eq!(next, None);
+    }
+
+    #[tokio::test]
+    async fn delete_removes_matching_points() {
+        let store = filled().await;
+
+        store
+            .delete(&by_author("alice"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            store
+                .count(&Filter::default())
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            store
+                .count(&by_author("alice"))
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_survives_close_and_open() {
+        let directory = std::env::temp_dir().join(format!(
+            "memory_store_{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+
+        let store = open(
+            MemoryMode::Persistent,
+            Some(directory.clone()),
+        )
+        .await;
+        store
+            .upsert(vec![point(
+                "a",
+                vec![1.0, 0.0],
+                "alice",
+            )])
+            .await
+            .unwrap();
+        store.close().await.unwrap();
+
+        let reopened = open(
+            MemoryMode::Persistent,
+            Some(directory.clone()),
+        )
+        .await;
+        let (points, _) = reopened
+            .scroll(&Filter::default(), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].vector, vec![1.0, 0.0]);
+
+        let reset = open(
+            MemoryMode::Reset,
+            Some(directory.clone()),
+        )
+        .await;
+        assert_eq!(
+            reset
+                .count(&Filter::default())
+                .await
+                .unwrap(),
+            0
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn snapshot_of_another_model_fails_to_open() {
+        let directory = std::env::temp_dir().join(format!(
+            "memory_store_{}",
+            uuid::Uuid::new_v4().simple()
+        ));
+        let store = open(
+            MemoryMode::Persistent,
+            Some(directory.clone()),
+        )
+        .await;
+        store.close().await.unwrap();
+
+        let other = MemoryStore::open(
+            "memories".to_string(),
+            &HashingEmbedder::new(3),
+            MemoryMode::Persistent,
+            OnMismatch::Fail,
+            Some(directory.clone()),
+        )
+        .await;
+        assert!(other.is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
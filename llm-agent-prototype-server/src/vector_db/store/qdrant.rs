@@ -0,0 +1,914 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use qdrant_client::{
+    prelude::QdrantClient,
+    qdrant::{
+        alias_operations::Action, point_id::PointIdOptions,
+        vectors::VectorsOptions, vectors_config::Config, AliasOperations,
+        ChangeAliases, CountPoints, CreateAlias, CreateCollection, DeleteAlias,
+        Distance, FieldType, PointId, PointStruct, Range, RetrievedPoint,
+        ScrollPoints, SearchPoints, Value, VectorParams, Vectors,
+        VectorsConfig,
+    },
+};
+
+use crate::vector_db::embeddings::Embedder;
+use crate::vector_db::store::{
+    self, Condition, FieldKind, Filter, MemoryMode, OnMismatch, Payload,
+    ScoredPoint, StoredPoint, VectorStore,
+};
+
+/// Distance of the embedding model.
+const DISTANCE: Distance = Distance::Cosine;
+
+/// Points read or written per request while re-embedding.
+const BATCH_SIZE: u32 = 256;
+
+/// Collection in a Qdrant server, addressed by a name that is an alias of
+/// the collection holding the points, named after the embedding model. A
+/// collection created under the name itself, as before aliases, is kept.
+pub(crate) struct QdrantStore {
+    client: Arc<QdrantClient>,
+    name: String,
+    mode: MemoryMode,
+    /// Payload fields indexed when the collection is created or opened.
+    indexes: Vec<(String, FieldKind)>,
+}
+
+impl std::fmt::Debug for QdrantStore {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("QdrantStore")
+            .field("name", &self.name)
+            .field("mode", &self.mode)
+            .finish()
+    }
+}
+
+impl QdrantStore {
+    /// Opens the collection in the mode, checking that it fits the
+    /// embedding model.
+    pub(crate) async fn open(
+        client: Arc<QdrantClient>,
+        name: String,
+        embedder: &dyn Embedder,
+        mode: MemoryMode,
+        on_mismatch: OnMismatch,
+        indexes: &[(&str, FieldKind)],
+    ) -> Result<Self> {
+        let name = match mode {
+            | MemoryMode::Ephemeral => format!(
+                "{}_{}",
+                name,
+                uuid::Uuid::new_v4().simple()
+            ),
+            | _ => name,
+        };
+
+        let dimension = embedder.dimension();
+        let collection = resolve(&client, &name).await?;
+
+        if let (Some(collection), MemoryMode::Reset) = (&collection, mode) {
+            drop_collection(&client, &name, collection).await?;
+        }
+
+        let store = Self {
+            client,
+            name,
+            mode,
+            indexes: indexes
+                .iter()
+                .map(|(key, kind)| (key.to_string(), *kind))
+                .collect(),
+        };
+
+        let collection = match collection {
+            | Some(collection) if mode != MemoryMode::Reset => {
+                store
+                    .create_missing_indexes(&collection)
+                    .await?;
+                collection
+            },
+            | _ => {
+                let collection = collection_for(&store.name, embedder);
+                store
+                    .create_collection(&collection, dimension)
+                    .await?;
+                store
+                    .client
+                    .create_alias(&collection, &store.name)
+                    .await
+                    .map_err(|error| {
+                        tracing::error!("Failed to create alias: {:?}", error);
+                        error
+                    })?;
+                collection
+            },
+        };
+
+        let tag = store::model_tag(embedder.model_id());
+        let mismatch = match model_of(&store.name, &collection) {
+            | Some(model) if model != tag => Some(format!(
+                "it was made for the model {} instead of {}",
+                model, tag
+            )),
+            | _ => find_mismatch(&store.client, &collection, dimension).await?,
+        };
+        if let Some(mismatch) = mismatch {
+            match on_mismatch {
+                | OnMismatch::Fail => {
+                    return Err(anyhow!(
+                        "Collection {} does not fit the embedding model {}: {}. \
+                         Set vector_store.on_mismatch = \"reembed\" to migrate \
+                         it or vector_store.mode = \"reset\" to discard it",
+                        store.name,
+                        embedder.model_id(),
+                        mismatch
+                    ));
+                },
+                | OnMismatch::Reembed => {
+                    tracing::warn!(
+                        "Re-embedding collection {}: {}",
+                        store.name,
+                        mismatch
+                    );
+                    store
+                        .reembed(embedder, &collection)
+                        .await?;
+                },
+            }
+        }
+
+        tracing::info!(
+            "Opened collection {} in {:?} mode",
+            store.name,
+            mode
+        );
+
+        Ok(store)
+    }
+
+    /// Creates a collection with the payload indexes.
+    async fn create_collection(
+        &self,
+        collection: &str,
+        dimension: u64,
+    ) -> Result<()> {
+        self.client
+            .create_collection(&CreateCollection {
+                collection_name: collection.to_string(),
+                vectors_config: Some(VectorsConfig {
+                    config: Some(Config::Params(VectorParams {
+                        size: dimension,
+                        distance: DISTANCE.into(),
+                        ..Default::default()
+                    })),
+                }),
+                ..Default::default()
+            })
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to create collection: {:?}",
+                    error
+                );
+                error
+            })?;
+
+        for (key, kind) in &self.indexes {
+            self.create_index(collection, key, *kind)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Creates the payload indexes an existing collection lacks, e.g. ones
+    /// added after it was created.
+    async fn create_missing_indexes(
+        &self,
+        collection: &str,
+    ) -> Result<()> {
+        let indexed = self
+            .client
+            .collection_info(collection)
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to get collection info: {:?}",
+                    error
+                );
+                error
+            })?
+            .result
+            .map(|info| info.payload_schema)
+            .unwrap_or_default();
+
+        for (key, kind) in &self.indexes {
+            if !indexed.contains_key(key) {
+                tracing::info!(
+                    "Indexing {} of collection {}",
+                    key,
+                    collection
+                );
+                self.create_index(collection, key, *kind)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn create_index(
+        &self,
+        collection: &str,
+        key: &str,
+        kind: FieldKind,
+    ) -> Result<()> {
+        let field_type = match kind {
+            | FieldKind::Keyword => FieldType::Keyword,
+            | FieldKind::Integer => FieldType::Integer,
+        };
+        self.client
+            .create_field_index_blocking(
+                collection, key, field_type, None, None,
+            )
+            .await
+            .map_err(|error| {
+                tracing::error!(
+                    "Failed to create payload index: {:?}",
+                    error
+                );
+                error
+            })?;
+
+        Ok(())
+    }
+
+    /// Moves the points into a new collection with vectors of the current
+    /// embedding model, a batch at a time, and then points the name at it.
+    /// The old collection is deleted only once the new one is complete,
+    /// and a swap cut short is finished on the next start.
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.reembed",
+        err,
+        skip(self, embedder)
+    )]
+    async fn reembed(
+        &self,
+        embedder: &dyn Embedder,
+        collection: &str,
+    ) -> Result<()> {
+        let target = collection_for(&self.name, embedder);
+        self.create_collection(&target, embedder.dimension())
+            .await?;
+
+        let count = match self
+            .copy_reembedded(embedder, &target)
+            .await
+        {
+            | Ok(count) => count,
+            | Err(error) => {
+                // The old collection is untouched, so only the copy goes.
+                if let Err(error) =
+                    delete_collection(&self.client, &target).await
+                {
+                    tracing::error!(
+                        "Failed to delete partial collection {}: {:?}",
+                        target,
+                        error
+                    );
+                }
+                return Err(error);
+            },
+        };
+
+        // Marks the copy as complete before the old collection goes.
+        self.client
+            .create_alias(&target, swapping_alias(&self.name))
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to create alias: {:?}", error);
+                error
+            })?;
+        if collection == self.name {
+            // A name cannot be both a collection and an alias.
+            delete_collection(&self.client, collection).await?;
+            swap_alias(&self.client, &self.name, &target, false).await?;
+        } else {
+            swap_alias(&self.client, &self.name, &target, true).await?;
+            delete_collection(&self.client, collection).await?;
+        }
+
+        tracing::info!(
+            "Re-embedded {} memories of {} into {}",
+            count,
+            self.name,
+            target
+        );
+
+        Ok(())
+    }
+
+    /// Copies every point into the collection with its text embedded again,
+    /// returning the number of points.
+    async fn copy_reembedded(
+        &self,
+        embedder: &dyn Embedder,
+        target: &str,
+    ) -> Result<usize> {
+        let mut count = 0;
+        let mut offset = None;
+        loop {
+            let (mut page, next) = self
+                .scroll(&Filter::default(), offset, BATCH_SIZE)
+                .await?;
+            let vectors = embedder
+                .embed_batch(store::texts(&page)?)
+                .await?;
+            for (point, vector) in page.iter_mut().zip(vectors) {
+                point.vector = vector;
+            }
+            count += page.len();
+            self.upsert_into(target, page)
+                .await?;
+
+            offset = next;
+            if offset.is_none() {
+                return Ok(count);
+            }
+        }
+    }
+
+    async fn upsert_into(
+        &self,
+        collection: &str,
+        points: Vec<StoredPoint>,
+    ) -> Result<()> {
+        let points = points
+            .into_iter()
+            .map(|point| {
+                Ok(PointStruct {
+                    id: Some(to_point_id(point.id)),
+                    payload: to_qdrant_payload(point.payload)?,
+                    vectors: Some(point.vector.into()),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.client
+            .upsert_points_blocking(collection, points, None)
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to upsert points: {:?}", error);
+                error
+            })?;
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl VectorStore for QdrantStore {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.upsert",
+        err,
+        skip(self, points)
+    )]
+    async fn upsert(
+        &self,
+        points: Vec<StoredPoint>,
+    ) -> Result<()> {
+        self.upsert_into(&self.name, points)
+            .await
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.search",
+        err,
+        skip(self, vector, filter)
+    )]
+    async fn search(
+        &self,
+        vector: Vec<f32>,
+        limit: u64,
+        filter: &Filter,
+    ) -> Result<Vec<ScoredPoint>> {
+        let result = self
+            .client
+            .search_points(&SearchPoints {
+                collection_name: self.name.clone(),
+                vector,
+                limit,
+                filter: to_qdrant_filter(filter),
+                with_payload: Some(true.into()),
+                with_vectors: Some(true.into()),
+                ..Default::default()
+            })
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to search points: {:?}", error);
+                error
+            })?;
+
+        Ok(result
+            .result
+            .into_iter()
+            .map(|point| ScoredPoint {
+                vector: to_vector(point.vectors),
+                score: point.score,
+                payload: to_payload(point.payload),
+            })
+            .collect())
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.delete",
+        err,
+        skip(self, filter)
+    )]
+    async fn delete(
+        &self,
+        filter: &Filter,
+    ) -> Result<()> {
+        let filter = to_qdrant_filter(filter).unwrap_or_default();
+        self.client
+            .delete_points(self.name.clone(), &filter.into(), None)
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to delete points: {:?}", error);
+                error
+            })?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.scroll",
+        err,
+        skip(self, filter)
+    )]
+    async fn scroll(
+        &self,
+        filter: &Filter,
+        offset: Option<String>,
+        limit: u32,
+    ) -> Result<(Vec<StoredPoint>, Option<String>)> {
+        let response = self
+            .client
+            .scroll(&ScrollPoints {
+                collection_name: self.name.clone(),
+                filter: to_qdrant_filter(filter),
+                offset: offset.map(to_point_id),
+                limit: Some(limit),
+                with_payload: Some(true.into()),
+                with_vectors: Some(true.into()),
+                ..Default::default()
+            })
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to scroll points: {:?}", error);
+                error
+            })?;
+
+        Ok((
+            response
+                .result
+                .into_iter()
+                .map(to_stored_point)
+                .collect(),
+            response
+                .next_page_offset
+                .map(from_point_id),
+        ))
+    }
+
+    #[tracing::instrument(
+        name = "vector_db.store.qdrant.count",
+        err,
+        skip(self, filter)
+    )]
+    async fn count(
+        &self,
+        filter: &Filter,
+    ) -> Result<u64> {
+        let response = self
+            .client
+            .count(&CountPoints {
+                collection_name: self.name.clone(),
+                filter: to_qdrant_filter(filter),
+                exact: Some(true),
+            })
+            .await
+            .map_err(|error| {
+                tracing::error!("Failed to count points: {:?}", error);
+                error
+            })?;
+
+        Ok(response
+            .result
+            .map(|result| result.count)
+            .unwrap_or_default())
+    }
+
+    /// Deletes the collection when it only lives for this run.
+    async fn close(&self) -> Result<()> {
+        if self.mode == MemoryMode::Ephemeral {
+            if let Some(collection) = resolve(&self.client, &self.name).await? {
+                drop_collection(&self.client, &self.name, &collection).await?;
+            }
+            tracing::info!(
+                "Deleted ephemeral collection {}",
+                self.name
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Qdrant filter of the conditions, none when there are none.
+fn to_qdrant_filter(filter: &Filter) -> Option<qdrant_client::qdrant::Filter> {
+    if filter.must.is_empty() && filter.should.is_empty() {
+        return None;
+    }
+
+    Some(qdrant_client::qdrant::Filter {
+        must: filter
+            .must
+            .iter()
+            .map(to_qdrant_condition)
+            .collect(),
+        should: filter
+            .should
+            .iter()
+            .map(to_qdrant_condition)
+            .collect(),
+        ..Default::default()
+    })
+}
+
+fn to_qdrant_condition(
+    condition: &Condition
+) -> qdrant_client::qdrant::Condition {
+    match condition {
+        | Condition::Equals {
+            key,
+            value,
+        } => qdrant_client::qdrant::Condition::matches(
+            key.as_str(),
+            value.clone(),
+        ),
+        | Condition::Range {
+            key,
+            gte,
+            lte,
+        } => qdrant_client::qdrant::Condition::range(
+            key.as_str(),
+            Range {
+                gte: *gte,
+                lte: *lte,
+                ..Default::default()
+            },
+        ),
+    }
+}
+
+fn to_qdrant_payload(payload: Payload) -> Result<HashMap<String, Value>> {
+    let payload = qdrant_client::prelude::Payload::try_from(
+        serde_json::Value::Object(payload),
+    )
+    .map_err(|error| {
+        tracing::error!("Failed to convert payload: {:?}", error);
+        anyhow!("Failed to convert payload: {:?}", error)
+    })?;
+
+    Ok(payload.into())
+}
+
+fn to_payload(payload: HashMap<String, Value>) -> Payload {
+    payload
+        .into_iter()
+        .map(|(key, value)| (key, value.into_json()))
+        .collect()
+}
+
+/// Point ids are UUIDs, or numbers for points written by other tools.
+fn to_point_id(id: String) -> PointId {
+    match id.parse::<u64>() {
+        | Ok(number) => number.into(),
+        | Err(_) => id.into(),
+    }
+}
+
+fn from_point_id(id: PointId) -> String {
+    match id.point_id_options {
+        | Some(PointIdOptions::Num(number)) => number.to_string(),
+        | Some(PointIdOptions::Uuid(uuid)) => uuid,
+        | None => String::new(),
+    }
+}
+
+/// The single vector of a point, empty when it was not returned.
+fn to_vector(vectors: Option<Vectors>) -> Vec<f32> {
+    match vectors.and_then(|vectors| vectors.vectors_options) {
+        | Some(VectorsOptions::Vector(vector)) => vector.data,
+        | _ => Vec::new(),
+    }
+}
+
+fn to_stored_point(point: RetrievedPoint) -> StoredPoint {
+    StoredPoint {
+        id: point
+            .id
+            .map(from_point_id)
+            .unwrap_or_default(),
+        vector: to_vector(point.vectors),
+        payload: to_payload(point.payload),
+    }
+}
+
+/// New collection behind the name for vectors of the embedding model, e.g.
+/// `long_memory_all-MiniLM-L6-v2_<uuid>`.
+fn collection_for(
+    name: &str,
+    embedder: &dyn Embedder,
+) -> String {
+    format!(
+        "{}_{}_{}",
+        name,
+        store::model_tag(embedder.model_id()),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// Model the collection behind the name was made for, none when it is not
+/// named after one.
+fn model_of<'a>(
+    name: &str,
+    collection: &'a str,
+) -> Option<&'a str> {
+    collection
+        .strip_prefix(name)?
+        .strip_prefix('_')?
+        .rsplit_once('_')
+        .map(|(model, _)| model)
+}
+
+/// Alias of a re-embedded collection while it replaces the old one.
+fn swapping_alias(name: &str) -> String {
+    format!("{}_swapping", name)
+}
+
+/// Collection behind the name, which is either one itself or an alias, none
+/// when there is none. Finishes a swap cut short after the old collection
+/// was deleted, and drops the copy of one cut short before.
+async fn resolve(
+    client: &QdrantClient,
+    name: &str,
+) -> Result<Option<String>> {
+    let aliases = client
+        .list_aliases()
+        .await
+        .map_err(|error| {
+            tracing::error!("Failed to list aliases: {:?}", error);
+            error
+        })?
+        .aliases;
+    let collection_of = |alias: &str| {
+        aliases
+            .iter()
+            .find(|description| description.alias_name == alias)
+            .map(|description| {
+                description
+                    .collection_name
+                    .clone()
+            })
+    };
+
+    let has_collection = client
+        .has_collection(name)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to check collection: {:?}",
+                error
+            );
+            error
+        })?;
+    let collection = match has_collection {
+        | true => Some(name.to_string()),
+        | false => collection_of(name),
+    };
+
+    match (
+        collection,
+        collection_of(&swapping_alias(name)),
+    ) {
+        | (collection, None) => Ok(collection),
+        | (Some(collection), Some(copy)) => {
+            tracing::warn!(
+                "Dropping collection {} left by an unfinished re-embedding of {}",
+                copy,
+                name
+            );
+            delete_alias(client, &swapping_alias(name)).await?;
+            delete_collection(client, &copy).await?;
+            Ok(Some(collection))
+        },
+        | (None, Some(copy)) => {
+            tracing::warn!(
+                "Finishing the swap of collection {} to {}",
+                name,
+                copy
+            );
+            swap_alias(client, name, &copy, false).await?;
+            Ok(Some(copy))
+        },
+    }
+}
+
+/// Points the name at the collection in one request, replacing the alias
+/// it was when requested, and drops the swapping alias.
+async fn swap_alias(
+    client: &QdrantClient,
+    name: &str,
+    collection: &str,
+    is_alias: bool,
+) -> Result<()> {
+    let mut actions = Vec::new();
+    if is_alias {
+        actions.push(Action::DeleteAlias(DeleteAlias {
+            alias_name: name.to_string(),
+        }));
+    }
+    actions.push(Action::CreateAlias(CreateAlias {
+        collection_name: collection.to_string(),
+        alias_name: name.to_string(),
+    }));
+    actions.push(Action::DeleteAlias(DeleteAlias {
+        alias_name: swapping_alias(name),
+    }));
+
+    client
+        .update_aliases(ChangeAliases {
+            actions: actions
+                .into_iter()
+                .map(|action| AliasOperations {
+                    action: Some(action),
+                })
+                .collect(),
+            timeout: None,
+        })
+        .await
+        .map_err(|error| {
+            tracing::error!("Failed to swap aliases: {:?}", error);
+            error
+        })?;
+
+    Ok(())
+}
+
+async fn delete_alias(
+    client: &QdrantClient,
+    name: &str,
+) -> Result<()> {
+    client
+        .delete_alias(name)
+        .await
+        .map_err(|error| {
+            tracing::error!("Failed to delete alias: {:?}", error);
+            error
+        })?;
+
+    Ok(())
+}
+
+/// Deletes the collection behind the name and the name when it is an alias.
+async fn drop_collection(
+    client: &QdrantClient,
+    name: &str,
+    collection: &str,
+) -> Result<()> {
+    if collection != name {
+        delete_alias(client, name).await?;
+    }
+
+    delete_collection(client, collection).await
+}
+
+async fn delete_collection(
+    client: &QdrantClient,
+    name: &str,
+) -> Result<()> {
+    client
+        .delete_collection(name)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to delete collection: {:?}",
+                error
+            );
+            error
+        })?;
+
+    Ok(())
+}
+
+/// Describes how the vectors of an existing collection differ from the
+/// embedding model, none when they fit.
+async fn find_mismatch(
+    client: &QdrantClient,
+    name: &str,
+    dimension: u64,
+) -> Result<Option<String>> {
+    let info = client
+        .collection_info(name)
+        .await
+        .map_err(|error| {
+            tracing::error!(
+                "Failed to get collection info: {:?}",
+                error
+            );
+            error
+        })?;
+
+    let config = info
+        .result
+        .and_then(|info| info.config)
+        .and_then(|config| config.params)
+        .and_then(|params| params.vectors_config)
+        .and_then(|vectors| vectors.config);
+    let Some(Config::Params(params)) = config else {
+        return Ok(Some(
+            "named vectors instead of a single one".to_string(),
+        ));
+    };
+
+    let mut problems = Vec::new();
+    if params.size != dimension {
+        problems.push(format!(
+            "vector size is {} instead of {}",
+            params.size, dimension
+        ));
+    }
+    if params.distance != DISTANCE as i32 {
+        problems.push(format!(
+            "distance is {:?} instead of {:?}",
+            Distance::from_i32(params.distance),
+            DISTANCE
+        ));
+    }
+
+    if problems.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(problems.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_of_reads_the_model_of_the_collection() {
+        assert_eq!(
+            model_of(
+                "long_memory",
+                "long_memory_all-MiniLM-L6-v2_0123abcd"
+            ),
+            Some("all-MiniLM-L6-v2")
+        );
+        assert_eq!(
+            model_of(
+                "long_memory",
+                "long_memory_text_embedding_0123abcd"
+            ),
+            Some("text_embedding")
+        );
+    }
+
+    #[test]
+    fn model_of_is_none_for_collections_without_a_model() {
+        assert_eq!(
+            model_of("long_memory", "long_memory"),
+            None
+        );
+        assert_eq!(
+            model_of("long_memory", "long_memory_0123abcd"),
+            None
+        );
+        assert_eq!(
+            model_of("long_memory", "other_memory_a_b"),
+            None
+        );
+    }
+}